@@ -0,0 +1,236 @@
+use crate::file_header::FileHeader;
+use crate::keydir::{EntryPointer, FileId};
+use crate::record::TxId;
+use crate::{Options, SyncBehavior};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// The file that all inserts and removes are appended to,
+/// along with everything else only the writer needs.
+#[derive(Debug)]
+pub(crate) struct ActiveFile {
+    db_directory: PathBuf,
+    options: Options,
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    file_id: FileId,
+    /// the current offset into the active file,
+    /// including anything that is still in the write buffer
+    offset: u64,
+    /// the current txid
+    tx_id: TxId,
+    /// bytes written since the active file was last synced
+    unsynced_bytes: u64,
+}
+
+impl ActiveFile {
+    pub(crate) async fn create(
+        db_directory: &Path,
+        file_id: FileId,
+        tx_id: TxId,
+        options: Options,
+    ) -> crate::Result<Self> {
+        let file = Self::create_file(db_directory, file_id, &options).await?;
+
+        Ok(Self {
+            db_directory: db_directory.to_owned(),
+            options,
+            file,
+            file_id,
+            offset: FileHeader::SIZE,
+            tx_id,
+            unsynced_bytes: 0,
+        })
+    }
+
+    pub(crate) fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    /// the txid to give the next record
+    pub(crate) fn next_tx_id(&mut self) -> TxId {
+        self.tx_id += 1;
+        self.tx_id
+    }
+
+    /// write the given bytes to the write buffer,
+    /// returning the offset in the active file they were written at
+    pub(crate) async fn write(&mut self, buf: &[u8]) -> crate::Result<u64> {
+        self.file.write_all(buf).await?;
+
+        let offset = self.offset;
+
+        self.offset += buf.len() as u64;
+
+        self.unsynced_bytes += buf.len() as u64;
+
+        Ok(offset)
+    }
+
+    pub(crate) async fn flush(&mut self, flushed_region: &FlushedRegion) -> crate::Result<()> {
+        self.file.flush().await?;
+
+        flushed_region.set(self.file_id, self.offset);
+
+        Ok(())
+    }
+
+    /// flush, and then make everything written so far durable
+    pub(crate) async fn sync(&mut self, flushed_region: &FlushedRegion) -> crate::Result<()> {
+        self.flush(flushed_region).await?;
+
+        self.file.get_ref().sync_data().await?;
+
+        self.unsynced_bytes = 0;
+
+        Ok(())
+    }
+
+    /// True if the `SyncBehavior` calls for a sync after the write that was just written.
+    /// `SyncBehavior::Interval` is synced by a background task instead.
+    pub(crate) fn needs_sync(&self) -> bool {
+        if self.unsynced_bytes == 0 {
+            return false;
+        }
+
+        match self.options.sync_behavior {
+            SyncBehavior::Never | SyncBehavior::Interval(_) => false,
+            SyncBehavior::AfterEveryWrite => true,
+            SyncBehavior::Bytes(bytes) => self.unsynced_bytes >= bytes,
+        }
+    }
+
+    /// true if anything has been written since the last sync
+    pub(crate) fn has_unsynced_writes(&self) -> bool {
+        self.unsynced_bytes > 0
+    }
+
+    /// true if the `SyncBehavior` syncs at all
+    pub(crate) fn syncs(&self) -> bool {
+        self.options.sync_behavior != SyncBehavior::Never
+    }
+
+    /// once the active file reaches the max file size,
+    /// make a new, empty active file
+    pub(crate) async fn rollover_if_full(
+        &mut self,
+        flushed_region: &FlushedRegion,
+    ) -> crate::Result<()> {
+        if self.offset >= self.options.max_file_size_bytes {
+            if self.syncs() {
+                self.sync(flushed_region).await?;
+            } else {
+                self.file.flush().await?;
+            }
+
+            self.file_id += 1;
+
+            self.file = Self::create_file(&self.db_directory, self.file_id, &self.options).await?;
+
+            self.offset = FileHeader::SIZE;
+
+            flushed_region.set(self.file_id, self.offset);
+        }
+
+        Ok(())
+    }
+
+    /// true if there is nothing in the write buffer,
+    /// and nothing that the `SyncBehavior` would sync
+    pub(crate) fn is_clean(&self) -> bool {
+        self.file.buffer().is_empty() && (!self.syncs() || self.unsynced_bytes == 0)
+    }
+
+    /// Flush, and sync if the `SyncBehavior` syncs at all, without a runtime,
+    /// for when the database is dropped without being closed.
+    /// This blocks the current thread, but never waits on a write that was cancelled
+    /// and is still running: it fails instead.
+    pub(crate) fn flush_blocking(&mut self) -> std::io::Result<()> {
+        let path = self.db_directory.join(self.file_id.to_string());
+
+        let mut std_file = std::fs::File::options().append(true).open(path)?;
+
+        let old_file = std::mem::replace(
+            &mut self.file,
+            tokio::io::BufWriter::new(tokio::fs::File::from_std(std_file.try_clone()?)),
+        );
+
+        let buffered = old_file.buffer().to_vec();
+
+        let old_file = old_file.into_inner();
+
+        // a write that was cancelled partway through may still be running
+        // on a blocking thread, and has to finish before anything else is appended
+        if old_file.try_into_std().is_err() {
+            return Err(std::io::Error::other("an earlier write is still running"));
+        }
+
+        // the buffer can still hold the start of a flush that was cancelled,
+        // which is already in the file
+        let unwritten = self.offset.saturating_sub(std_file.metadata()?.len()) as usize;
+
+        std::io::Write::write_all(
+            &mut std_file,
+            &buffered[buffered.len().saturating_sub(unwritten)..],
+        )?;
+
+        if self.syncs() {
+            std_file.sync_data()?;
+            self.unsynced_bytes = 0;
+        }
+
+        Ok(())
+    }
+}
+
+// private impls
+impl ActiveFile {
+    async fn create_file(
+        db_directory: &Path,
+        file_id: FileId,
+        options: &Options,
+    ) -> crate::Result<tokio::io::BufWriter<tokio::fs::File>> {
+        let mut path = db_directory.to_owned();
+        path.push(file_id.to_string());
+
+        let mut file = tokio::fs::File::options()
+            .append(true)
+            .create_new(true)
+            .open(path)
+            .await?;
+
+        FileHeader::write_new(&mut file, options).await?;
+
+        // so that the new file is still there after a power loss
+        crate::base::sync_directory(db_directory).await?;
+
+        Ok(tokio::io::BufWriter::with_capacity(
+            options.write_buffer_size,
+            file,
+        ))
+    }
+}
+
+/// How much of the active file has been flushed to disk.
+/// Readers check this to see if they can read a value
+/// without waiting on the writer.
+#[derive(Debug)]
+pub(crate) struct FlushedRegion(std::sync::Mutex<(FileId, u64)>);
+
+impl FlushedRegion {
+    pub(crate) fn new(active_file_id: FileId) -> Self {
+        Self(std::sync::Mutex::new((active_file_id, 0)))
+    }
+
+    /// true if the value the entry points to is on disk
+    pub(crate) fn contains(&self, entry: &EntryPointer) -> bool {
+        let (active_file_id, flushed_offset) = *self.0.lock().unwrap();
+
+        // every file other than the active file is fully flushed
+        entry.file_id != active_file_id
+            || entry.value_position + entry.value_size.0 <= flushed_offset
+    }
+
+    fn set(&self, active_file_id: FileId, flushed_offset: u64) {
+        *self.0.lock().unwrap() = (active_file_id, flushed_offset);
+    }
+}
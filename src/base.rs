@@ -1,8 +1,25 @@
+use crate::active_file::{ActiveFile, FlushedRegion};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::codec::Codec;
+use crate::compression::CompressionKind;
+use crate::directory_lock::DirectoryLock;
+use crate::encoding::Encoding;
+use crate::file_header::FileHeader;
+use crate::file_stats::{FileStats, FileStatsTable};
+use crate::follower::{FollowState, Followed};
+use crate::high_water_mark::HighWaterMark;
+use crate::hint::Hint;
 use crate::keydir::{EntryPointer, EntryWithLiveness, FileId, Keydir, Liveness};
+use crate::merge::{MergeControl, MergeSelection};
+use crate::merge_generation::MergeGeneration;
+use crate::merge_manifest::{merge_file_paths, MergeManifest};
 use crate::merge_pointer::MergePointer;
-use crate::record::{Record, TxId};
-use crate::Options;
+use crate::metadata::Metadata;
+use crate::record::{ExpiresAt, Record, RecordKind, SizeFields, Timestamp, TxId, ValueHeader};
+use crate::recovery::RecoveryReport;
+use crate::write_queue::{Queued, ResultSender, Write, WriteQueue};
 use crate::{error, FlushBehavior};
+use crate::{Options, RecoveryMode};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::collections::HashMap;
@@ -10,7 +27,14 @@ use std::fmt::Debug;
 use std::hash::Hash;
 use std::path::{Path, PathBuf};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-
+use tokio::sync::OwnedRwLockReadGuard;
+
+/// Readers and the writer do not block each other.
+/// Readers only hold the keydir lock long enough to copy an `EntryPointer` out of it,
+/// and the writer only holds it long enough to update it after a write.
+/// Only one writer at a time holds the active file.
+///
+/// Merge needs `&mut self`, so it excludes both readers and the writer.
 #[derive(Debug)]
 pub(crate) struct Base<K>
 where
@@ -18,13 +42,37 @@ where
 {
     db_directory: PathBuf,
     options: Options,
-    keydir: Keydir<K>,
-    active_file: tokio::io::BufWriter<tokio::fs::File>,
-    active_file_id: FileId,
-    /// the current offset into the current active file
-    offset: u64,
-    /// the current txid
-    tx_id: TxId,
+    /// the codec and encryption from `options`
+    encoding: Encoding,
+    keydir: std::sync::RwLock<Keydir<K>>,
+    /// `None` when the database is open read-only
+    active_file: Option<tokio::sync::Mutex<ActiveFile>>,
+    /// how much of the active file can be read without waiting on the writer
+    flushed_region: FlushedRegion,
+    /// how much of each data file is dead, updated along with the keydir
+    file_stats: std::sync::Mutex<FileStatsTable>,
+    /// writes waiting for the active file
+    write_queue: WriteQueue<K>,
+    /// what was dropped when loading data files
+    recovery_report: std::sync::Mutex<RecoveryReport>,
+    /// how far a follower has read. `None` unless the database is open as a follower
+    follow_state: Option<tokio::sync::Mutex<FollowState>>,
+    /// held for as long as the database is open.
+    /// declared last, so that it is released only after everything else is dropped
+    /// `None` when the database is open read-only
+    _directory_lock: Option<DirectoryLock>,
+}
+
+/// How a database is opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OpenMode {
+    /// the only mode that can write
+    ReadWrite,
+    /// without changing anything in the database directory,
+    /// and without taking the directory lock
+    ReadOnly,
+    /// read-only, keeping up with whatever another process writes
+    Follower,
 }
 
 // public impls
@@ -32,8 +80,31 @@ impl<K> Base<K>
 where
     K: Eq + Hash + Serialize + DeserializeOwned + Send,
 {
-    pub(crate) async fn new(db_directory: &Path, options: Options) -> crate::Result<Self> {
-        let mut db_file_ids = Self::all_db_file_ids(db_directory).await?;
+    pub(crate) async fn new(
+        db_directory: &Path,
+        options: Options,
+        open_mode: OpenMode,
+    ) -> crate::Result<Self> {
+        let directory_lock = match open_mode {
+            OpenMode::ReadWrite => {
+                let directory_lock = DirectoryLock::acquire(db_directory).await?;
+                crate::merge_manifest::finish_interrupted_merge(db_directory).await?;
+                Some(directory_lock)
+            }
+            OpenMode::ReadOnly | OpenMode::Follower => {
+                // finishing the merge would change the directory
+                if MergeManifest::read(db_directory).await?.is_some() {
+                    return Err(error::Error::MergeNotFinished);
+                }
+                None
+            }
+        };
+
+        let recovery_mode = Self::recovery_mode(&options, open_mode);
+
+        let encoding = Encoding::new(&options);
+
+        let mut db_file_ids = all_db_file_ids(db_directory).await?;
 
         db_file_ids.sort();
 
@@ -43,68 +114,88 @@ where
 
         let active_file_id = latest_file_id + 1;
 
-        let all_entries_with_livenesses: HashMap<K, EntryWithLiveness> =
-            crate::loadable::load_latest_entries(db_directory, &db_file_ids).await?;
-
-        let all_entries: HashMap<K, EntryPointer> = all_entries_with_livenesses
-            .into_iter()
-            .filter_map(|(key, entry_with_liveness)| {
-                if entry_with_liveness.liveness == Liveness::Deleted {
-                    None
-                } else {
-                    Some((key, entry_with_liveness.entry))
-                }
-            })
-            .collect();
+        let mut recovery_report = RecoveryReport::default();
 
-        let keydir = Keydir::from(all_entries);
+        // a follower loads everything in `catch_up`
+        if open_mode == OpenMode::Follower {
+            db_file_ids.clear();
+        }
 
-        let latest_tx_id = keydir.latest_tx_id().unwrap_or(0.into());
+        let (keydir, mut file_stats, latest_tx_id) = Self::load(
+            db_directory,
+            &db_file_ids,
+            &encoding,
+            recovery_mode,
+            &mut recovery_report,
+        )
+        .await?;
+
+        // merging can drop the records with the largest `TxId`s
+        let latest_tx_id = latest_tx_id
+            .max(HighWaterMark::read(db_directory).await?)
+            .unwrap_or(0.into());
+
+        let active_file = match open_mode {
+            OpenMode::ReadWrite => {
+                let active_file = ActiveFile::create(
+                    db_directory,
+                    active_file_id,
+                    latest_tx_id + 1,
+                    options.clone(),
+                )
+                .await?;
 
-        let mut active_file_path = db_directory.to_owned();
-        active_file_path.push(active_file_id.to_string());
+                file_stats.written(active_file_id, FileHeader::SIZE);
 
-        let active_file = tokio::fs::File::options()
-            .append(true)
-            .create_new(true)
-            .open(active_file_path)
-            .await?;
+                Some(tokio::sync::Mutex::new(active_file))
+            }
+            OpenMode::ReadOnly | OpenMode::Follower => None,
+        };
 
-        let active_file =
-            tokio::io::BufWriter::with_capacity(options.write_buffer_size, active_file);
+        let follow_state = match open_mode {
+            OpenMode::Follower => Some(tokio::sync::Mutex::new(FollowState::default())),
+            OpenMode::ReadWrite | OpenMode::ReadOnly => None,
+        };
 
-        Ok(Self {
+        let base = Self {
             db_directory: db_directory.to_owned(),
             options,
-            keydir,
+            encoding,
+            keydir: std::sync::RwLock::new(keydir),
             active_file,
-            active_file_id,
-            offset: 0,
-            tx_id: latest_tx_id + 1,
-        })
+            // when read-only, there is no active file,
+            // and every data file counts as flushed
+            flushed_region: FlushedRegion::new(active_file_id),
+            file_stats: std::sync::Mutex::new(file_stats),
+            write_queue: WriteQueue::new(),
+            recovery_report: std::sync::Mutex::new(recovery_report),
+            follow_state,
+            _directory_lock: directory_lock,
+        };
+
+        base.catch_up().await?;
+
+        Ok(base)
     }
 
     pub(crate) async fn get<V: Serialize + DeserializeOwned + Send>(
         &self,
         k: &K,
     ) -> crate::Result<Option<V>> {
-        if let Some(entry) = self.keydir.get(k) {
-            let mut path = self.db_directory.clone();
-            path.push(entry.file_id.to_string());
-
-            let mut f = tokio::fs::File::open(path).await?;
-
-            f.seek(std::io::SeekFrom::Start(entry.value_position))
-                .await?;
-
-            let mut buf = vec![0u8; entry.value_size.0 as usize];
+        let stored = match self.read_stored_value(k).await {
+            // the writer merged the file the keydir pointed into.
+            // when read-only, catching up does nothing, so this returns the error
+            Err(error::Error::RecordMoved) => {
+                self.catch_up().await?;
+                self.read_stored_value(k).await?
+            }
+            stored => stored?,
+        };
 
-            f.read_exact(&mut buf).await?;
+        if let Some(stored) = stored {
+            let buf = stored.compression.decompress(stored.value)?;
 
-            let v: V = bincode::deserialize(&buf).map_err(|e| error::DeserializeError {
-                msg: "unable to deserialize from bincode".to_string(),
-                source: e,
-            })?;
+            let v: V = self.encoding.codec.decode(&buf)?;
 
             Ok(Some(v))
         } else {
@@ -112,30 +203,120 @@ where
         }
     }
 
-    pub(crate) async fn insert<V: Serialize + DeserializeOwned + Send>(
-        &mut self,
+    /// the write for an insert, ready to be passed to `write`
+    pub(crate) fn prepare_insert<V: Serialize + DeserializeOwned + Send>(
+        &self,
         k: K,
         v: V,
-    ) -> crate::Result<()> {
-        self.write_insert(k, v).await
+        expires_at: ExpiresAt,
+    ) -> crate::Result<Write<K>> {
+        let encoded_key = self.encoding.codec.encode(&k)?;
+        let encoded_value = self.encoding.codec.encode(&v)?;
+
+        let op = self.prepare(BatchOp::Insert {
+            key: k,
+            encoded_key,
+            encoded_value,
+            compression: CompressionKind::None,
+            expires_at,
+        })?;
+
+        Ok(Write::Single(op))
     }
 
-    pub(crate) async fn remove(&mut self, k: K) -> crate::Result<()> {
-        if self.keydir.contains_key(&k) {
-            self.write_delete(k).await
-        } else {
-            Ok(())
+    pub(crate) fn prepare_remove(&self, k: K) -> crate::Result<Write<K>> {
+        let encoded_key = self.encoding.codec.encode(&k)?;
+
+        let op = self.prepare(BatchOp::Remove {
+            key: k,
+            encoded_key,
+        })?;
+
+        Ok(Write::Single(op))
+    }
+
+    /// `None` if the batch is empty, so there is nothing to write
+    pub(crate) fn prepare_batch(&self, batch: WriteBatch<K>) -> crate::Result<Option<Write<K>>> {
+        if batch.codec() != self.encoding.codec {
+            return Err(error::Error::BatchCodecMismatch);
+        }
+
+        if batch.is_empty() {
+            return Ok(None);
+        }
+
+        let ops = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| self.prepare(op))
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        Ok(Some(Write::Batch(ops)))
+    }
+
+    /// Queue the write, and then wait for whoever gets the active file
+    /// to write it along with everything else that is queued. See `WriteQueue`.
+    ///
+    /// The writing happens in a task of its own, which holds `base` until it is done,
+    /// so a caller that stops waiting can't leave a group half written.
+    pub(crate) async fn write(
+        base: OwnedRwLockReadGuard<Self>,
+        write: Write<K>,
+    ) -> crate::Result<()>
+    where
+        K: Sync + 'static,
+    {
+        base.active_file()?;
+
+        let (result_receiver, starts_group) = base.write_queue.push(write);
+
+        // otherwise the task that was started for the group has yet to take it
+        if starts_group {
+            tokio::spawn(async move {
+                let Ok(active_file) = base.active_file() else {
+                    return;
+                };
+
+                let mut active_file = active_file.lock().await;
+
+                let queued = base.write_queue.take();
+
+                let results = base.write_group(&mut active_file, queued).await;
+
+                drop(active_file);
+
+                // so that a writer that has its result can close the database straight away
+                drop(base);
+
+                for (result_sender, result) in results {
+                    let _ = result_sender.send(result);
+                }
+            });
         }
+
+        result_receiver
+            .await
+            .unwrap_or(Err(error::Error::WriteInterrupted))
     }
 
     pub(crate) fn contains_key(&self, k: &K) -> bool {
-        self.keydir.contains_key(k)
+        self.keydir.read().unwrap().contains_key(k)
     }
 
-    pub(crate) fn keys(&self) -> std::collections::hash_map::Keys<'_, K, EntryPointer> {
-        self.keydir.keys()
+    pub(crate) fn metadata(&self, k: &K) -> Option<Metadata> {
+        self.keydir
+            .read()
+            .unwrap()
+            .get(k)
+            .filter(|entry| !entry.is_expired())
+            .map(Metadata::from)
     }
 
+    /// The first, slow part of a merge: copying the latest live records
+    /// of the selected inactive files into new `.merge` files.
+    /// This only needs `&self`, so reads and writes carry on while it runs.
+    /// `commit_merge` then swaps the `.merge` files in.
+    ///
     /// # invariants
     ///
     /// ### affected data files
@@ -147,7 +328,8 @@ where
     /// - for a given key `K`, only the record with the latest TXID
     ///   may remain in the dataset.
     /// - if the record for a given key `K` with the latest TXID is a delete,
-    ///   it SHALL NOT appear in the inactive data files
+    ///   it SHALL NOT appear in the inactive data files,
+    ///   unless some inactive data files are not being merged
     ///
     /// ### cannot add data
     /// - the total size of all data on disk AFTER merge
@@ -156,144 +338,224 @@ where
     ///
     /// ### no dangling files
     /// - all database files SHALL have a size > 0 bytes.
-    pub(crate) async fn merge(&mut self) -> crate::Result<()> {
-        let mut inactive_db_files = self.inactive_db_file_ids().await?;
+    pub(crate) async fn write_merge_files(
+        &self,
+        control: &MergeControl,
+        selection: MergeSelection<'_>,
+    ) -> crate::Result<MergeOutcome<K>> {
+        match self.write_merge_files_inner(control, selection).await {
+            Ok(outcome) => Ok(outcome),
+            Err(e) => {
+                // leave the directory as it was before the merge
+                for merge_path in merge_file_paths(&self.db_directory).await? {
+                    tokio::fs::remove_file(merge_path).await?;
+                }
 
-        let merge_pointers: HashMap<K, MergePointer> =
-            crate::loadable::load_latest_entries(&self.db_directory, &inactive_db_files).await?;
+                Err(e)
+            }
+        }
+    }
 
-        let live_merge_pointers = merge_pointers
-            .into_iter()
-            .filter(|(_key, merge_pointer)| merge_pointer.liveness == Liveness::Live);
+    /// The second, quick part of a merge, which needs `&mut self`
+    /// so that no reader is partway through reading a file that is about to be removed.
+    /// See `MergeManifest` for how this survives the process dying partway through.
+    pub(crate) async fn commit_merge(&mut self, outcome: MergeOutcome<K>) -> crate::Result<()> {
+        let manifest = outcome.manifest();
 
-        let mut current_write_file: Option<tokio::io::BufWriter<tokio::fs::File>> = None;
+        manifest.write(&self.db_directory).await?;
 
-        let mut offset = 0;
+        manifest.apply(&self.db_directory).await?;
 
-        for (key, merge_pointer) in live_merge_pointers {
-            //
-            if let Some(entry) = self.keydir.get(&key) {
-                // IFF the entry has been written to the active file,
-                // do not process it at all, as its latest version
-                // is later than anything seen by the merge process,
-                // as the merge process only touches inactive files
-                if entry.tx_id > merge_pointer.tx_id {
-                    continue;
-                }
-            }
+        MergeManifest::remove(&self.db_directory).await?;
 
-            let mut current_write_file_id = inactive_db_files.pop().unwrap();
-
-            let mut current_file_path = self.db_directory.to_owned();
-            let mut current_file_name = current_write_file_id.to_string();
-            current_file_name.push_str(".merge");
-            current_file_path.push(current_file_name);
-
-            // db directory
-            // merge pointers
-            // max file size in bytes
-            // nonactive_file_ids
-            if let Some(write_file) = current_write_file.as_mut() {
-                if offset > self.options.max_file_size_bytes {
-                    write_file.flush().await?;
-
-                    current_write_file_id = inactive_db_files.pop().unwrap();
-                    // current_file_path = self.db_directory.to_owned();
-                    self.db_directory.clone_into(&mut current_file_path);
-                    current_file_name = current_write_file_id.to_string();
-                    current_file_name.push_str(".merge");
-                    current_file_path.push(current_file_name);
-
-                    let write_file = tokio::fs::File::options()
-                        .append(true)
-                        .create_new(true)
-                        .open(&current_file_path)
-                        .await?;
-
-                    current_write_file = Some(tokio::io::BufWriter::new(write_file));
-                }
+        let keydir = self.keydir.get_mut().unwrap();
+
+        let file_stats = self.file_stats.get_mut().unwrap();
+
+        // merge outputs reuse the ids of the files they replace
+        for file_id in &outcome.inputs {
+            file_stats.remove(*file_id);
+        }
+
+        for (file_id, len) in &outcome.outputs {
+            file_stats.written(*file_id, *len);
+        }
+
+        for (key, new_entry) in outcome.entries {
+            // the key may have been inserted or removed while the merge files were written,
+            // in which case its entry is already newer than what the merge copied
+            if keydir
+                .get(&key)
+                .is_some_and(|entry| entry.tx_id == new_entry.tx_id)
+            {
+                keydir.insert(key, new_entry);
             } else {
-                let write_file = tokio::fs::File::options()
-                    .append(true)
-                    .create_new(true)
-                    .open(&current_file_path)
-                    .await?;
+                file_stats.superseded(new_entry.file_id, new_entry.record_size);
+            }
+        }
 
-                current_write_file = Some(tokio::io::BufWriter::new(write_file));
+        for (key, tx_id) in outcome.expired {
+            // unless the key has been inserted again since
+            if keydir.get(&key).is_some_and(|entry| entry.tx_id == tx_id) {
+                keydir.remove(&key);
             }
+        }
 
-            let mut reader_path = self.db_directory.to_owned();
+        let dropped = &mut self.recovery_report.get_mut().unwrap().dropped;
 
-            reader_path.push(merge_pointer.file_id.to_string());
+        // the database may have dropped the same when it loaded the inputs
+        for dropped_range in outcome.recovery_report.dropped {
+            if !dropped.contains(&dropped_range) {
+                dropped.push(dropped_range);
+            }
+        }
 
-            let read_file = tokio::fs::File::open(reader_path).await?;
+        Ok(())
+    }
 
-            let mut read_file = tokio::io::BufReader::new(read_file);
+    pub(crate) fn recovery_report(&self) -> RecoveryReport {
+        self.recovery_report.lock().unwrap().clone()
+    }
 
-            read_file
-                .seek(std::io::SeekFrom::Start(merge_pointer.record_offset))
-                .await?;
+    pub(crate) fn file_stats(&self) -> Vec<FileStats> {
+        self.file_stats.lock().unwrap().to_vec()
+    }
 
-            let mut take_handle = read_file.take(merge_pointer.record_size);
+    /// Read whatever the writer has written since the last catch up,
+    /// if the database is open as a follower.
+    /// If the writer has merged since then, this loads everything again.
+    pub(crate) async fn catch_up(&self) -> crate::Result<()> {
+        let Some(follow_state) = &self.follow_state else {
+            return Ok(());
+        };
 
-            let bytes_read = {
-                // DUMB, BAD
-                let mut writer = current_write_file.unwrap();
+        let mut follow_state = follow_state.lock().await;
 
-                let bytes_read = tokio::io::copy_buf(&mut take_handle, &mut writer).await?;
+        // the writer is swapping merged files in, so try again later
+        if MergeManifest::read(&self.db_directory).await?.is_some() {
+            return Ok(());
+        }
 
-                // DUMB, BAD
-                current_write_file = Some(writer);
+        // before anything is read, so that a merge that happens while reading
+        // is noticed the next time
+        let merge_generation = MergeGeneration::read(&self.db_directory).await?;
 
-                bytes_read
-            };
+        let mut db_file_ids = all_db_file_ids(&self.db_directory).await?;
 
-            assert!(bytes_read == merge_pointer.record_size);
+        db_file_ids.sort();
 
-            let value_position = offset
-                + crate::record::Record::HEADER_SIZE as u64
-                + merge_pointer.key_size.0 as u64;
+        let Some((tail_file_id, read_file_ids)) = db_file_ids.split_last() else {
+            return Ok(());
+        };
 
-            offset += merge_pointer.record_size;
+        let recovery_mode = Self::recovery_mode(&self.options, OpenMode::Follower);
+
+        if !follow_state
+            .is_current(&self.db_directory, &db_file_ids, merge_generation)
+            .await?
+        {
+            let mut recovery_report = RecoveryReport::default();
+
+            let (keydir, file_stats, _latest_tx_id) = Self::load(
+                &self.db_directory,
+                read_file_ids,
+                &self.encoding,
+                recovery_mode,
+                &mut recovery_report,
+            )
+            .await?;
 
-            let new_entry = EntryPointer {
-                file_id: current_write_file_id,
-                value_position,
-                value_size: merge_pointer.value_size,
-                tx_id: merge_pointer.tx_id,
-            };
+            *self.keydir.write().unwrap() = keydir;
+            *self.file_stats.lock().unwrap() = file_stats;
+            *self.recovery_report.lock().unwrap() = recovery_report;
 
-            self.keydir.insert(key, new_entry);
+            follow_state.reset(*tail_file_id, merge_generation);
         }
 
-        if let Some(mut write_file) = current_write_file {
-            write_file.flush().await?;
+        for file_id in follow_state.unread_file_ids(&db_file_ids) {
+            let mut recovery_report = RecoveryReport::default();
+
+            let offset = follow_state.offset(file_id);
+
+            let followed: Followed<K> = crate::follower::read_from(
+                &self.db_directory,
+                file_id,
+                offset,
+                &self.encoding,
+                recovery_mode,
+                &mut recovery_report,
+            )
+            .await?;
+
+            self.recovery_report
+                .lock()
+                .unwrap()
+                .dropped
+                .extend(recovery_report.dropped);
+
+            let read_to = followed.offset;
+
+            self.apply_followed(file_id, read_to - offset, followed)?;
+
+            if file_id == *tail_file_id {
+                follow_state.read_tail(file_id, read_to);
+            }
         }
 
-        // rm all inactive db files
-        for file_id in self.inactive_db_file_ids().await? {
-            let mut filename = self.db_directory.clone();
-            filename.push(file_id.to_string());
-            tokio::fs::remove_file(filename).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&self) -> crate::Result<()> {
+        if let Some(active_file) = &self.active_file {
+            let mut active_file = active_file.lock().await;
+            active_file.flush(&self.flushed_region).await?;
         }
 
-        // rename all .merge files
-        for merge_path in self.merge_files().await? {
-            if tokio::fs::metadata(&merge_path).await?.len() > 0 {
-                let normal_filename = merge_path.file_stem().unwrap();
-                let mut normal_path = self.db_directory.clone();
-                normal_path.push(normal_filename);
-                tokio::fs::rename(&merge_path, normal_path).await?;
-            } else {
-                tokio::fs::remove_file(merge_path).await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn lock_active_file(&self) -> tokio::sync::MutexGuard<'_, ActiveFile> {
+        self.active_file().unwrap().lock().await
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn wait_for_queued_writes(&self, n: usize) {
+        while self.write_queue.len() < n {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// when read-only, there is nothing to sync
+    pub(crate) async fn sync(&self) -> crate::Result<()> {
+        if let Some(active_file) = &self.active_file {
+            let mut active_file = active_file.lock().await;
+            active_file.sync(&self.flushed_region).await?;
+        }
+
+        Ok(())
+    }
+
+    /// sync, unless nothing has been written since the last sync
+    pub(crate) async fn sync_unsynced(&self) -> crate::Result<()> {
+        if let Some(active_file) = &self.active_file {
+            let mut active_file = active_file.lock().await;
+
+            if active_file.has_unsynced_writes() {
+                active_file.sync(&self.flushed_region).await?;
             }
         }
 
         Ok(())
     }
+}
 
-    pub(crate) async fn flush(&mut self) -> crate::Result<()> {
-        self.active_file.flush().await.map_err(|e| e.into())
+impl<K> Base<K>
+where
+    K: Clone + Eq + Hash + Serialize + DeserializeOwned + Send,
+{
+    pub(crate) fn keys(&self) -> Vec<K> {
+        self.keydir.read().unwrap().keys().cloned().collect()
     }
 }
 
@@ -302,166 +564,928 @@ impl<K> Base<K>
 where
     K: Eq + Hash + Serialize + DeserializeOwned + Send,
 {
-    // TODO investigate whether we can collapse write_delete and write_insert
-    async fn write_insert<V: Serialize + DeserializeOwned + Send>(
-        &mut self,
-        k: K,
-        v: V,
-    ) -> crate::Result<()> {
-        self.tx_id += 1;
+    /// Compress an insert or delete that has only been serialized,
+    /// as `insert`, `remove` and `WriteBatch` do, and encrypt its key,
+    /// and check that the result fits within the size limits.
+    /// The value is encrypted once its `TxId` is known, in `Group::push`.
+    fn prepare(&self, op: BatchOp<K>) -> crate::Result<BatchOp<K>> {
+        match op {
+            BatchOp::Insert {
+                key,
+                encoded_key,
+                encoded_value,
+                expires_at,
+                ..
+            } => {
+                let (compression, encoded_value) = self
+                    .options
+                    .compression
+                    .compress(encoded_value, self.options.compression_threshold)?;
+
+                let encoded_key = self.check_key_size(self.encoding.seal_key(encoded_key))?;
+
+                let encoded_value = self.check_value_size(encoded_value)?;
+
+                Ok(BatchOp::Insert {
+                    key,
+                    encoded_key,
+                    encoded_value,
+                    compression,
+                    expires_at,
+                })
+            }
+            BatchOp::Remove { key, encoded_key } => Ok(BatchOp::Remove {
+                key,
+                encoded_key: self.check_key_size(self.encoding.seal_key(encoded_key))?,
+            }),
+        }
+    }
+
+    fn check_key_size(&self, encoded_key: Vec<u8>) -> crate::Result<Vec<u8>> {
+        let size = encoded_key.len() as u64;
+        let limit = self.options.key_size_limit();
+
+        if size > limit {
+            return Err(error::Error::KeyTooLarge { size, limit });
+        }
 
-        let record = Record::new(&k, &v, self.tx_id)?;
+        Ok(encoded_key)
+    }
+
+    /// the value is checked at the size it will be stored at, once it is encrypted
+    fn check_value_size(&self, encoded_value: Vec<u8>) -> crate::Result<Vec<u8>> {
+        let size = self.encoding.sealed_len(encoded_value.len() as u64);
+        let limit = self.options.value_size_limit();
+
+        if size > limit {
+            return Err(error::Error::ValueTooLarge { size, limit });
+        }
 
-        self.active_file.write_all(&record).await?;
+        Ok(encoded_value)
+    }
 
-        let value_position =
-            self.offset + crate::record::Record::HEADER_SIZE as u64 + record.key_size().0 as u64;
+    /// Write everything that was queued with a single write and a single flush or sync,
+    /// and return the result for every writer.
+    async fn write_group(
+        &self,
+        active_file: &mut ActiveFile,
+        queued: Vec<Queued<K>>,
+    ) -> Vec<(ResultSender, crate::Result<()>)> {
+        let active_file_id = active_file.file_id();
+
+        let mut group = Group::new(self.encoding.size_fields);
+
+        let mut results = Vec::with_capacity(queued.len());
+
+        let mut result_senders = Vec::with_capacity(queued.len());
+
+        for (write, result_sender) in queued {
+            match write {
+                Write::Single(op) => {
+                    // there is nothing to delete
+                    if let BatchOp::Remove { key, .. } = &op {
+                        if !self.group_will_contain(&group, key) {
+                            results.push((result_sender, Ok(())));
+                            continue;
+                        }
+                    }
+
+                    group.push(
+                        &self.encoding,
+                        active_file,
+                        active_file_id,
+                        RecordKind::Standalone,
+                        op,
+                    );
+                }
+                Write::Batch(ops) => {
+                    let batch_len = ops.len() as u32;
+
+                    for op in ops {
+                        group.push(
+                            &self.encoding,
+                            active_file,
+                            active_file_id,
+                            RecordKind::Batched,
+                            op,
+                        );
+                    }
+
+                    let commit = Record::batch_commit(
+                        self.encoding.size_fields,
+                        active_file.next_tx_id(),
+                        Timestamp::now(),
+                        batch_len,
+                    );
+
+                    // the commit record is only needed until the batch is merged
+                    group.dead_bytes += commit.len() as u64;
+
+                    group.buf.extend_from_slice(&commit);
+                }
+            }
+
+            result_senders.push(result_sender);
+        }
 
-        let entry = EntryPointer {
-            file_id: self.active_file_id,
-            value_position,
-            value_size: record.value_size(),
-            tx_id: self.tx_id,
+        let result = if group.buf.is_empty() {
+            Ok(())
+        } else {
+            self.write_and_apply(active_file, active_file_id, group)
+                .await
         };
 
-        self.keydir.insert(k, entry);
+        for result_sender in result_senders {
+            results.push((
+                result_sender,
+                match &result {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(e.duplicate()),
+                },
+            ));
+        }
+
+        results
+    }
 
-        let entry_size = crate::record::Record::HEADER_SIZE
-            + record.key_size().0 as usize
-            + record.value_size().0 as usize;
+    /// true if the key will exist once the writes so far in the group are applied
+    fn group_will_contain(&self, group: &Group<K>, key: &K) -> bool {
+        match group.changes.iter().rev().find(|(k, _)| k == key) {
+            Some((_, entry)) => entry.is_some(),
+            // only the writer changes the keydir outside of merge,
+            // so this can't change while we hold the active file
+            None => self.keydir.read().unwrap().contains_key(key),
+        }
+    }
 
-        self.offset += entry_size as u64;
+    /// the keydir is only changed once the whole group has been written
+    async fn write_and_apply(
+        &self,
+        active_file: &mut ActiveFile,
+        active_file_id: FileId,
+        group: Group<K>,
+    ) -> crate::Result<()> {
+        let group_offset = active_file.write(&group.buf).await?;
 
-        if self.offset >= self.options.max_file_size_bytes {
-            self.active_file.flush().await?;
+        self.finish_write(active_file).await?;
 
-            self.active_file_id += 1;
+        let mut keydir = self.keydir.write().unwrap();
 
-            let mut new_active_file_path = self.db_directory.clone();
+        let mut file_stats = self.file_stats.lock().unwrap();
 
-            new_active_file_path.push(self.active_file_id.to_string());
+        file_stats.written(active_file_id, group.buf.len() as u64);
 
-            let active_file = tokio::fs::File::options()
-                .append(true)
-                .create_new(true)
-                .open(new_active_file_path)
-                .await?;
+        file_stats.superseded(active_file_id, group.dead_bytes);
 
-            let active_file = tokio::io::BufWriter::new(active_file);
-            self.active_file = active_file;
+        for (key, entry) in group.changes {
+            let previous = if let Some(mut entry) = entry {
+                entry.value_position += group_offset;
+                keydir.insert(key, entry)
+            } else {
+                keydir.remove(&key)
+            };
+
+            if let Some(previous) = previous {
+                file_stats.superseded(previous.file_id, previous.record_size);
+            }
         }
 
-        if self.options.flush_behavior == FlushBehavior::AfterEveryWrite {
-            self.flush().await
-        } else {
-            Ok(())
+        Ok(())
+    }
+
+    /// everything that happens after a write, but before it is made visible to readers.
+    /// when writes are flushed after every write, readers never have to wait on the writer.
+    async fn finish_write(&self, active_file: &mut ActiveFile) -> crate::Result<()> {
+        if active_file.needs_sync() {
+            active_file.sync(&self.flushed_region).await?;
+        } else if self.options.flush_behavior == FlushBehavior::AfterEveryWrite {
+            active_file.flush(&self.flushed_region).await?;
+        }
+
+        let file_id = active_file.file_id();
+
+        active_file.rollover_if_full(&self.flushed_region).await?;
+
+        if active_file.file_id() != file_id {
+            self.file_stats
+                .lock()
+                .unwrap()
+                .written(active_file.file_id(), FileHeader::SIZE);
         }
+
+        Ok(())
     }
 
-    async fn write_delete(&mut self, k: K) -> crate::Result<()> {
-        self.tx_id += 1;
+    async fn write_merge_files_inner(
+        &self,
+        control: &MergeControl,
+        selection: MergeSelection<'_>,
+    ) -> crate::Result<MergeOutcome<K>> {
+        let inactive_file_ids = self.inactive_db_file_ids().await?;
+
+        let inputs = self.merge_inputs(&inactive_file_ids, selection)?;
+
+        // deletes can only be dropped when every record they could delete is being merged
+        let merging_everything = inputs.len() == inactive_file_ids.len();
+
+        let mut recovery_report = RecoveryReport::default();
+
+        // readers may still be reading the inputs, so a merge never truncates them.
+        // it skips what the database skips, and otherwise stops at anything corrupt
+        let recovery_mode = match self.options.recovery_mode {
+            RecoveryMode::SkipCorrupt => RecoveryMode::SkipCorrupt,
+            RecoveryMode::Strict | RecoveryMode::TruncateTail => RecoveryMode::Strict,
+        };
+
+        let (merge_pointers, latest_tx_id): (HashMap<K, MergePointer>, _) =
+            crate::loadable::load_latest_entries(
+                &self.db_directory,
+                &inputs,
+                &self.encoding,
+                recovery_mode,
+                &mut recovery_report,
+            )
+            .await?;
+
+        // before anything is dropped, so that its `TxId` is never given out again
+        if let Some(latest_tx_id) = latest_tx_id {
+            HighWaterMark::raise(&self.db_directory, latest_tx_id).await?;
+        }
+
+        // expired records are dropped along with deletes,
+        // and as with deletes, only when they can't be shadowing anything
+        let (merge_pointers, dropped): (Vec<_>, Vec<_>) =
+            merge_pointers
+                .into_iter()
+                .partition(|(_key, merge_pointer)| {
+                    !merging_everything
+                        || (merge_pointer.liveness == Liveness::Live
+                            && !merge_pointer.expires_at.has_passed())
+                });
+
+        let expired = dropped
+            .into_iter()
+            .filter(|(_key, merge_pointer)| merge_pointer.liveness == Liveness::Live)
+            .map(|(key, merge_pointer)| (key, merge_pointer.tx_id))
+            .collect();
+
+        control.set_keys_total(merge_pointers.len() as u64);
 
-        let v = Record::tombstone();
+        let mut unused_file_ids = inputs.clone();
 
-        let record = Record::new(&k, &v, self.tx_id)?;
+        let mut entries = Vec::with_capacity(merge_pointers.len());
 
-        self.active_file.write_all(&record).await?;
+        let mut outputs = vec![];
 
-        self.keydir.remove(&k);
+        let mut current_output: Option<MergeOutput> = None;
 
-        let entry_size = crate::record::Record::HEADER_SIZE
-            + record.key_size().0 as usize
-            + record.value_size().0 as usize;
+        let mut input_readers = HashMap::new();
 
-        self.offset += entry_size as u64;
+        for (key, merge_pointer) in merge_pointers {
+            if control.is_cancelled() {
+                return Err(error::Error::MergeCancelled);
+            }
 
-        if self.offset >= self.options.max_file_size_bytes {
-            self.active_file.flush().await?;
+            control.key_processed();
 
-            self.active_file_id += 1;
+            let latest_tx_id = self.keydir.read().unwrap().get(&key).map(|e| e.tx_id);
 
-            let mut new_active_file_path = self.db_directory.clone();
+            match (latest_tx_id, merge_pointer.liveness) {
+                // IFF the entry has been written to a file that is not being merged,
+                // do not process it at all, as its latest version
+                // is later than anything seen by the merge process
+                (Some(latest_tx_id), _) if latest_tx_id > merge_pointer.tx_id => continue,
+                // the key has since been deleted by a file that is not being merged
+                (None, Liveness::Live) => continue,
+                _ => (),
+            }
 
-            new_active_file_path.push(self.active_file_id.to_string());
+            // migrating a record to the current format can make it larger,
+            // so there may be more to write than fits in as many files as were merged.
+            // once every input's id is taken, the last output takes the rest
+            let needs_new_output = match &current_output {
+                Some(output) => {
+                    output.offset > self.options.max_file_size_bytes && !unused_file_ids.is_empty()
+                }
+                None => true,
+            };
 
-            let active_file = tokio::fs::File::options()
-                .append(true)
-                .create_new(true)
-                .open(new_active_file_path)
+            if needs_new_output {
+                if let Some(mut output) = current_output.take() {
+                    output.flush().await?;
+                    outputs.push((output.file_id, output.offset));
+                }
+
+                let file_id = unused_file_ids.pop().unwrap();
+
+                current_output =
+                    Some(MergeOutput::create(&self.db_directory, file_id, &self.options).await?);
+            }
+
+            let output = current_output.as_mut().unwrap();
+
+            let read_file = match input_readers.entry(merge_pointer.file_id) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let f = tokio::fs::File::open(
+                        self.db_directory.join(merge_pointer.file_id.to_string()),
+                    )
+                    .await?;
+
+                    entry.insert(tokio::io::BufReader::new(f))
+                }
+            };
+
+            read_file
+                .seek(std::io::SeekFrom::Start(merge_pointer.record_offset))
                 .await?;
 
-            let active_file = tokio::io::BufWriter::new(active_file);
-            self.active_file = active_file;
+            let record = match Record::read_from(
+                read_file,
+                self.encoding.size_fields,
+                merge_pointer.format_version,
+            )
+            .await?
+            {
+                Some(record) => record,
+                None => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            };
+
+            // the file changed since it was loaded
+            if record.len() as u64 != merge_pointer.record_size || !record.is_valid() {
+                return Err(error::Error::CorruptRecord);
+            }
+
+            // a record from a committed batch no longer needs its batch,
+            // a record from an older data file may need migrating,
+            // and its value may need compressing differently
+            let record = record
+                .into_standalone(&self.encoding)?
+                .into_current_format()
+                .recompress(
+                    self.options.compression,
+                    self.options.compression_threshold,
+                    &self.encoding,
+                )?;
+
+            output.data_file.write_all(&record).await?;
+
+            let value_position = output.offset + record.value_offset();
+
+            output.offset += record.len() as u64;
+
+            let hint = Hint::new(&record, value_position);
+
+            output.hint_file.write_all(&hint).await?;
+
+            if merge_pointer.liveness == Liveness::Live {
+                let new_entry = EntryPointer {
+                    file_id: output.file_id,
+                    value_position,
+                    value_size: record.value_size(),
+                    record_size: record.len() as u64,
+                    compression: record.compression(),
+                    tx_id: merge_pointer.tx_id,
+                    timestamp: merge_pointer.timestamp,
+                    expires_at: merge_pointer.expires_at,
+                };
+
+                entries.push((key, new_entry));
+            }
         }
 
-        if self.options.flush_behavior == FlushBehavior::AfterEveryWrite {
-            self.flush().await
-        } else {
-            Ok(())
+        if let Some(mut output) = current_output {
+            output.flush().await?;
+            outputs.push((output.file_id, output.offset));
+        }
+
+        sync_directory(&self.db_directory).await?;
+
+        if control.is_cancelled() {
+            return Err(error::Error::MergeCancelled);
+        }
+
+        Ok(MergeOutcome {
+            inputs,
+            outputs,
+            entries,
+            expired,
+            recovery_report,
+        })
+    }
+
+    /// the inactive files that a merge with the given selection merges
+    fn merge_inputs(
+        &self,
+        inactive_file_ids: &[FileId],
+        selection: MergeSelection<'_>,
+    ) -> crate::Result<Vec<FileId>> {
+        match selection {
+            MergeSelection::All => Ok(inactive_file_ids.to_vec()),
+            MergeSelection::Files(file_ids) => {
+                let mut selected = file_ids.to_vec();
+
+                selected.sort();
+                selected.dedup();
+
+                if let Some(file_id) = selected
+                    .iter()
+                    .find(|file_id| !inactive_file_ids.contains(file_id))
+                {
+                    return Err(error::Error::NotAnInactiveFile(*file_id));
+                }
+
+                Ok(selected)
+            }
+            MergeSelection::Policy(policy) => {
+                let file_stats = self.file_stats.lock().unwrap();
+
+                let selected: Vec<FileId> = inactive_file_ids
+                    .iter()
+                    .copied()
+                    .filter(|file_id| {
+                        file_stats
+                            .get(*file_id)
+                            .is_some_and(|stats| policy.selects(stats))
+                    })
+                    .collect();
+
+                if selected.len() >= policy.min_files {
+                    Ok(selected)
+                } else {
+                    Ok(vec![])
+                }
+            }
         }
     }
 
-    async fn all_db_file_ids(db_directory: &Path) -> crate::Result<Vec<FileId>> {
-        let mut file_ids = vec![];
+    /// the keydir and file stats for the given data files,
+    /// and the largest `TxId` of any record in them
+    async fn load(
+        db_directory: &Path,
+        db_file_ids: &[FileId],
+        encoding: &Encoding,
+        recovery_mode: RecoveryMode,
+        recovery_report: &mut RecoveryReport,
+    ) -> crate::Result<(Keydir<K>, FileStatsTable, Option<TxId>)> {
+        let (all_entries_with_livenesses, latest_tx_id): (HashMap<K, EntryWithLiveness>, _) =
+            crate::loadable::load_latest_entries(
+                db_directory,
+                db_file_ids,
+                encoding,
+                recovery_mode,
+                recovery_report,
+            )
+            .await?;
 
-        let mut dir_reader = tokio::fs::read_dir(db_directory).await?;
+        let file_stats =
+            Self::load_file_stats(db_directory, db_file_ids, &all_entries_with_livenesses).await?;
 
-        while let Some(dir_entry) = dir_reader.next_entry().await? {
-            if dir_entry.file_type().await?.is_file() {
-                let path = dir_entry.path();
-                let file_name = path.file_name().unwrap().to_owned();
-                let file_name = file_name.to_str().unwrap();
-                let file_name = file_name.to_owned();
-                if let Ok(file_id) = file_name.parse() {
-                    file_ids.push(file_id);
+        let all_entries: HashMap<K, EntryPointer> = all_entries_with_livenesses
+            .into_iter()
+            .filter_map(|(key, entry_with_liveness)| {
+                if entry_with_liveness.liveness == Liveness::Deleted {
+                    None
+                } else {
+                    Some((key, entry_with_liveness.entry))
                 }
+            })
+            .collect();
+
+        Ok((Keydir::from(all_entries), file_stats, latest_tx_id))
+    }
+
+    /// truncating a torn tail would change the directory,
+    /// and when not writing, the tail may only be torn
+    /// because the writer is partway through writing it
+    fn recovery_mode(options: &Options, open_mode: OpenMode) -> RecoveryMode {
+        match (open_mode, options.recovery_mode) {
+            (OpenMode::ReadOnly | OpenMode::Follower, RecoveryMode::TruncateTail) => {
+                RecoveryMode::SkipCorrupt
             }
+            (_, recovery_mode) => recovery_mode,
+        }
+    }
+
+    /// apply what a follower read from the given file to the keydir and file stats
+    fn apply_followed(
+        &self,
+        file_id: FileId,
+        read_len: u64,
+        followed: Followed<K>,
+    ) -> crate::Result<()> {
+        let mut keydir = self.keydir.write().unwrap();
+
+        let mut file_stats = self.file_stats.lock().unwrap();
+
+        file_stats.written(file_id, read_len);
+
+        file_stats.superseded(file_id, followed.dead_bytes);
+
+        for (key, entry_with_liveness) in followed.entries {
+            let previous = match entry_with_liveness.liveness {
+                Liveness::Live => keydir.insert(key, entry_with_liveness.entry),
+                Liveness::Deleted => keydir.remove(&key),
+            };
+
+            if let Some(previous) = previous {
+                file_stats.superseded(previous.file_id, previous.record_size);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// everything in a data file that is not the latest record for its key is dead.
+    /// deletes that are the latest record for their key are not dead,
+    /// as they are still needed to shadow older records.
+    async fn load_file_stats(
+        db_directory: &Path,
+        db_file_ids: &[FileId],
+        latest_entries: &HashMap<K, EntryWithLiveness>,
+    ) -> crate::Result<FileStatsTable> {
+        let mut live_bytes: HashMap<FileId, u64> = HashMap::new();
+
+        for entry_with_liveness in latest_entries.values() {
+            let entry = &entry_with_liveness.entry;
+
+            *live_bytes.entry(entry.file_id).or_default() += entry.record_size;
+        }
+
+        let mut file_stats = FileStatsTable::default();
+
+        for file_id in db_file_ids {
+            let total_bytes = crate::recovery::data_file_len(db_directory, *file_id).await?;
+
+            let path = db_directory.join(file_id.to_string());
+
+            let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(&path).await?);
+
+            // the header is never dead, and version 0 files have none
+            let header_bytes = match FileHeader::read_from(&mut reader, &path).await {
+                Ok(Some(header)) => header.records_start(),
+                _ => FileHeader::SIZE.min(total_bytes),
+            };
+
+            let live_bytes = live_bytes.get(file_id).copied().unwrap_or_default() + header_bytes;
+
+            file_stats.written(*file_id, total_bytes);
+
+            file_stats.superseded(*file_id, total_bytes.saturating_sub(live_bytes));
+        }
+
+        Ok(file_stats)
+    }
+
+    /// The value for the key, decrypted but as it is otherwise stored, if the key exists.
+    ///
+    /// Unless this is the writer, the keydir can point into files
+    /// that the writer has since merged, so a follower or a read-only database
+    /// reads and checks the whole record,
+    /// and returns `Error::RecordMoved` if it is not the record the keydir was loaded from.
+    /// An encrypted value is sealed with its record's header, so it is read the same way.
+    async fn read_stored_value(&self, k: &K) -> crate::Result<Option<StoredValue>> {
+        let entry = self.keydir.read().unwrap().get(k).cloned();
+
+        let Some(entry) = entry.filter(|entry| !entry.is_expired()) else {
+            return Ok(None);
+        };
+
+        // a value that is still in the write buffer
+        // has to be flushed before it can be read
+        if !self.flushed_region.contains(&entry) {
+            self.flush().await?;
+        }
+
+        let mut path = self.db_directory.clone();
+        path.push(entry.file_id.to_string());
+
+        if self.active_file.is_none() || self.encoding.is_encrypted() {
+            return self.read_checked_value(k, &entry, &path).await.map(Some);
         }
 
-        Ok(file_ids)
+        let mut f = tokio::fs::File::open(path).await?;
+
+        f.seek(std::io::SeekFrom::Start(entry.value_position))
+            .await?;
+
+        let mut value = vec![0u8; entry.value_size.0 as usize];
+
+        f.read_exact(&mut value).await?;
+
+        Ok(Some(StoredValue {
+            compression: entry.compression,
+            value,
+        }))
     }
 
+    /// the value of the record `entry` points to,
+    /// or `Error::RecordMoved` if that record is not there
+    async fn read_checked_value(
+        &self,
+        k: &K,
+        entry: &EntryPointer,
+        path: &Path,
+    ) -> crate::Result<StoredValue> {
+        fn moved<E>(_: E) -> error::Error {
+            error::Error::RecordMoved
+        }
+
+        // the file may have been merged away
+        let f = tokio::fs::File::open(path).await.map_err(moved)?;
+
+        let mut reader = tokio::io::BufReader::new(f);
+
+        let header = FileHeader::read_from(&mut reader, path)
+            .await
+            .map_err(moved)?
+            .ok_or(error::Error::RecordMoved)?;
+
+        let encoding = self.encoding.for_file(&header);
+
+        let record_position = entry
+            .value_position
+            .checked_sub(
+                Record::header_size_for(header.size_fields(), header.version) as u64
+                    + encoding.encoded_key_len(k)?,
+            )
+            .ok_or(error::Error::RecordMoved)?;
+
+        reader
+            .seek(std::io::SeekFrom::Start(record_position))
+            .await
+            .map_err(moved)?;
+
+        let record = Record::read_from(&mut reader, header.size_fields(), header.version)
+            .await
+            .map_err(moved)?
+            .ok_or(error::Error::RecordMoved)?;
+
+        if !record.is_valid()
+            || record.tx_id() != entry.tx_id
+            || record.key::<K>(&encoding).ok().as_ref() != Some(k)
+        {
+            return Err(error::Error::RecordMoved);
+        }
+
+        Ok(StoredValue {
+            compression: entry.compression,
+            value: encoding.open_value(
+                record.value_bytes().to_vec(),
+                record.key_bytes(),
+                record.value_header(),
+            )?,
+        })
+    }
+
+    /// returns `Error::ReadOnly` if the database is open read-only
+    fn active_file(&self) -> crate::Result<&tokio::sync::Mutex<ActiveFile>> {
+        self.active_file.as_ref().ok_or(error::Error::ReadOnly)
+    }
+
+    /// every data file older than the active file.
+    /// files made by the writer while a merge runs are newer than that,
+    /// so a merge never touches them.
     async fn inactive_db_file_ids(&self) -> crate::Result<Vec<FileId>> {
-        let mut db_file_ids = Self::all_db_file_ids(&self.db_directory).await?;
+        let active_file_id = self.active_file()?.lock().await.file_id();
 
-        db_file_ids.retain(|file_id| *file_id != self.active_file_id);
+        let mut db_file_ids = all_db_file_ids(&self.db_directory).await?;
+
+        db_file_ids.retain(|file_id| *file_id < active_file_id);
 
         Ok(db_file_ids)
     }
+}
 
-    async fn merge_files(&self) -> crate::Result<Vec<PathBuf>> {
-        let mut file_ids = vec![];
+/// make the creation, removal and renaming of files in the given directory durable
+pub(crate) async fn sync_directory(db_directory: &Path) -> crate::Result<()> {
+    tokio::fs::File::open(db_directory)
+        .await?
+        .sync_all()
+        .await?;
+    Ok(())
+}
 
-        let mut dir_reader = tokio::fs::read_dir(&self.db_directory).await?;
+/// the ids of all data files in the given directory, in no particular order
+pub(crate) async fn all_db_file_ids(db_directory: &Path) -> crate::Result<Vec<FileId>> {
+    let mut file_ids = vec![];
 
-        while let Some(dir_entry) = dir_reader.next_entry().await? {
-            if dir_entry.file_type().await?.is_file() {
-                let path = dir_entry.path();
+    let mut dir_reader = tokio::fs::read_dir(db_directory).await?;
 
-                if let Some(extension) = path.extension() {
-                    if extension == "merge" {
-                        file_ids.push(path);
-                    }
-                }
+    while let Some(dir_entry) = dir_reader.next_entry().await? {
+        if dir_entry.file_type().await?.is_file() {
+            let path = dir_entry.path();
+            let file_name = path.file_name().unwrap().to_owned();
+            let file_name = file_name.to_str().unwrap();
+            let file_name = file_name.to_owned();
+            if let Ok(file_id) = file_name.parse() {
+                file_ids.push(file_id);
             }
         }
-
-        Ok(file_ids)
     }
+
+    Ok(file_ids)
 }
 
+/// `B2::close` leaves nothing for this to do.
+/// Otherwise this is a best-effort fallback,
+/// and what it could not write is only reported by `close`.
 impl<K: Eq + Hash + Serialize + DeserializeOwned + Send> Drop for Base<K> {
     fn drop(&mut self) {
-        std::thread::scope(|s| {
-            s.spawn(|| {
-                let rt = tokio::runtime::Builder::new_current_thread()
-                    .build()
-                    .unwrap();
-                rt.block_on(async {
-                    // TODO is sync_all/sync_data necessary here?
-                    let _ = self.flush().await;
-                });
-            });
-        });
+        let Some(active_file) = self.active_file.as_mut() else {
+            return;
+        };
+
+        let active_file = active_file.get_mut();
+
+        if active_file.is_clean() {
+            return;
+        }
+
+        // there is no one to return an error to
+        let _ = active_file.flush_blocking();
+    }
+}
+
+/// a value as it is read from its record and decrypted, before it is decompressed
+struct StoredValue {
+    compression: CompressionKind,
+    value: Vec<u8>,
+}
+
+/// the records of a group of writes, before they are written
+struct Group<K> {
+    buf: Vec<u8>,
+    /// (key, and the entry for it if it is an insert,
+    /// with its value position relative to the start of `buf`)
+    changes: Vec<(K, Option<EntryPointer>)>,
+    /// bytes of `buf` that are dead as soon as they are written
+    dead_bytes: u64,
+    size_fields: SizeFields,
+}
+
+impl<K> Group<K> {
+    fn new(size_fields: SizeFields) -> Self {
+        Self {
+            buf: vec![],
+            changes: vec![],
+            dead_bytes: 0,
+            size_fields,
+        }
+    }
+
+    fn push(
+        &mut self,
+        encoding: &Encoding,
+        active_file: &mut ActiveFile,
+        active_file_id: FileId,
+        kind: RecordKind,
+        op: BatchOp<K>,
+    ) {
+        let tx_id = active_file.next_tx_id();
+        let timestamp = Timestamp::now();
+
+        match op {
+            BatchOp::Insert {
+                key,
+                encoded_key,
+                encoded_value,
+                compression,
+                expires_at,
+            } => {
+                let encoded_value = encoding.seal_value(
+                    encoded_value,
+                    &encoded_key,
+                    ValueHeader {
+                        kind,
+                        compression,
+                        tx_id,
+                        timestamp,
+                        expires_at,
+                    },
+                );
+
+                let record = Record::from_encoded(
+                    self.size_fields,
+                    kind,
+                    compression,
+                    tx_id,
+                    timestamp,
+                    expires_at,
+                    &encoded_key,
+                    &encoded_value,
+                );
+
+                let value_position = self.buf.len() as u64 + record.value_offset();
+
+                let entry = EntryPointer {
+                    file_id: active_file_id,
+                    value_position,
+                    value_size: record.value_size(),
+                    record_size: record.len() as u64,
+                    compression,
+                    tx_id,
+                    timestamp,
+                    expires_at,
+                };
+
+                self.buf.extend_from_slice(&record);
+
+                self.changes.push((key, Some(entry)));
+            }
+            BatchOp::Remove { key, encoded_key } => {
+                let record = Record::delete(self.size_fields, kind, tx_id, timestamp, &encoded_key);
+
+                self.buf.extend_from_slice(&record);
+
+                self.changes.push((key, None));
+            }
+        }
+    }
+}
+
+/// what the first part of a merge hands to the second part
+pub(crate) struct MergeOutcome<K> {
+    /// the data files that were merged
+    inputs: Vec<FileId>,
+    /// the data files that replace them, and their sizes
+    outputs: Vec<(FileId, u64)>,
+    /// where each merged key's latest record ended up
+    entries: Vec<(K, EntryPointer)>,
+    /// keys whose latest record had expired, and was dropped
+    expired: Vec<(K, TxId)>,
+    /// what was dropped when loading the data files that were merged
+    recovery_report: RecoveryReport,
+}
+
+/// a data file and its hint file, being written by a merge
+struct MergeOutput {
+    file_id: FileId,
+    data_file: tokio::io::BufWriter<tokio::fs::File>,
+    hint_file: tokio::io::BufWriter<tokio::fs::File>,
+    /// the current offset into the data file
+    offset: u64,
+}
+
+impl<K> MergeOutcome<K> {
+    /// true if there was nothing to merge
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub(crate) fn manifest(&self) -> MergeManifest {
+        MergeManifest {
+            inputs: self.inputs.clone(),
+            outputs: self
+                .outputs
+                .iter()
+                .map(|(file_id, _len)| *file_id)
+                .collect(),
+        }
+    }
+}
+
+impl MergeOutput {
+    async fn create(
+        db_directory: &Path,
+        file_id: FileId,
+        options: &Options,
+    ) -> crate::Result<Self> {
+        let mut data_file_path = db_directory.to_owned();
+        data_file_path.push(format!("{}.merge", *file_id));
+
+        let mut data_file = tokio::fs::File::options()
+            .append(true)
+            .create_new(true)
+            .open(data_file_path)
+            .await?;
+
+        FileHeader::write_new(&mut data_file, options).await?;
+
+        let mut hint_file_path = db_directory.to_owned();
+        hint_file_path.push(format!("{}.{}.merge", *file_id, Hint::FILE_EXTENSION));
+
+        let hint_file = tokio::fs::File::options()
+            .append(true)
+            .create_new(true)
+            .open(hint_file_path)
+            .await?;
+
+        Ok(Self {
+            file_id,
+            data_file: tokio::io::BufWriter::new(data_file),
+            hint_file: tokio::io::BufWriter::new(hint_file),
+            offset: FileHeader::SIZE,
+        })
+    }
+
+    /// flush and sync, as a merge must not be committed
+    /// until everything it wrote is on disk
+    async fn flush(&mut self) -> crate::Result<()> {
+        self.data_file.flush().await?;
+        self.data_file.get_ref().sync_all().await?;
+        self.hint_file.flush().await?;
+        self.hint_file.get_ref().sync_all().await?;
+        Ok(())
     }
 }
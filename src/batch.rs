@@ -0,0 +1,121 @@
+use crate::codec::{Codec, CodecKind};
+use crate::compression::CompressionKind;
+use crate::record::ExpiresAt;
+use serde::Serialize;
+use std::time::Duration;
+
+/// A set of inserts and removes that are written together,
+/// and that take effect all together or not at all.
+///
+/// If the process dies while a batch is being written,
+/// none of it is visible when the database is next opened.
+///
+/// Values are serialized as they are added, so a batch has to be built
+/// with the same codec as the database it is written to, as `B2::new_batch` does.
+#[derive(Debug)]
+pub struct WriteBatch<K> {
+    ops: Vec<BatchOp<K>>,
+    codec: CodecKind,
+}
+
+#[derive(Debug)]
+pub(crate) enum BatchOp<K> {
+    Insert {
+        key: K,
+        encoded_key: Vec<u8>,
+        /// compressed with `compression`, and not yet encrypted
+        encoded_value: Vec<u8>,
+        compression: CompressionKind,
+        expires_at: ExpiresAt,
+    },
+    Remove {
+        key: K,
+        encoded_key: Vec<u8>,
+    },
+}
+
+impl<K: Serialize> WriteBatch<K> {
+    /// A batch for a database that uses the default codec, bincode.
+    /// Writing it to a database with any other codec fails with `Error::BatchCodecMismatch`,
+    /// so prefer `B2::new_batch`, which uses the database's codec.
+    pub fn new() -> Self {
+        Self::with_codec(CodecKind::default())
+    }
+
+    /// a batch for a database that uses the given codec
+    pub fn with_codec(codec: CodecKind) -> Self {
+        Self { ops: vec![], codec }
+    }
+
+    /// Add an insert of the given key and value to the batch.
+    /// The value is serialized immediately.
+    pub fn insert<V: Serialize>(&mut self, k: K, v: V) -> crate::Result<()> {
+        self.push_insert(k, v, ExpiresAt::NEVER)
+    }
+
+    /// Add an insert of the given key and value to the batch,
+    /// which expires `ttl` after the batch is built. See `B2::insert_with_ttl`.
+    pub fn insert_with_ttl<V: Serialize>(
+        &mut self,
+        k: K,
+        v: V,
+        ttl: Duration,
+    ) -> crate::Result<()> {
+        self.push_insert(k, v, ExpiresAt::after(ttl))
+    }
+
+    /// Add a delete of the given key to the batch.
+    pub fn remove(&mut self, k: K) -> crate::Result<()> {
+        let encoded_key = self.codec.encode(&k)?;
+
+        self.ops.push(BatchOp::Remove {
+            key: k,
+            encoded_key,
+        });
+
+        Ok(())
+    }
+
+    /// The number of inserts and removes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp<K>> {
+        self.ops
+    }
+
+    fn push_insert<V: Serialize>(
+        &mut self,
+        k: K,
+        v: V,
+        expires_at: ExpiresAt,
+    ) -> crate::Result<()> {
+        let encoded_key = self.codec.encode(&k)?;
+        let encoded_value = self.codec.encode(&v)?;
+
+        self.ops.push(BatchOp::Insert {
+            key: k,
+            encoded_key,
+            encoded_value,
+            compression: CompressionKind::None,
+            expires_at,
+        });
+
+        Ok(())
+    }
+}
+
+impl<K: Serialize> Default for WriteBatch<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,161 @@
+//! How keys and values are serialized to the bytes stored in data files.
+//!
+//! A database uses the one codec set in `Options::codec` for everything it writes,
+//! and each data file's header records which codec it was written with.
+//! bincode is always available, and the rest each need a cargo feature:
+//! `postcard`, `msgpack` and `json`.
+
+#[cfg(any(feature = "postcard", feature = "msgpack", feature = "json"))]
+use crate::error::{CodecDeserializeError, CodecSerializeError};
+use crate::error::{DeserializeError, SerializeError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Turns keys and values into bytes, and back.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(&self, t: &T) -> crate::Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T>;
+}
+
+/// [bincode 1](https://docs.rs/bincode/1), which is compact,
+/// but not self-describing, and not easily read outside of Rust
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(&self, t: &T) -> crate::Result<Vec<u8>> {
+        Ok(bincode::serialize(t).map_err(bincode_serialize_error)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        Ok(
+            bincode::deserialize(bytes).map_err(|source| DeserializeError {
+                msg: "unable to deserialize from bincode".to_string(),
+                source,
+            })?,
+        )
+    }
+}
+
+/// [postcard](https://docs.rs/postcard), which is more compact than bincode
+#[cfg(feature = "postcard")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn encode<T: Serialize + ?Sized>(&self, t: &T) -> crate::Result<Vec<u8>> {
+        Ok(postcard::to_allocvec(t).map_err(|e| CodecSerializeError::new("postcard", e))?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        Ok(postcard::from_bytes(bytes).map_err(|e| CodecDeserializeError::new("postcard", e))?)
+    }
+}
+
+/// [MessagePack](https://msgpack.org), with structs as maps of field names,
+/// so that it can be read without knowing the Rust types
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize + ?Sized>(&self, t: &T) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(t).map_err(|e| CodecSerializeError::new("MessagePack", e))?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        Ok(rmp_serde::from_slice(bytes)
+            .map_err(|e| CodecDeserializeError::new("MessagePack", e))?)
+    }
+}
+
+/// JSON, which is the least compact, and the easiest to read anywhere
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, t: &T) -> crate::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(t).map_err(|e| CodecSerializeError::new("JSON", e))?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        Ok(serde_json::from_slice(bytes).map_err(|e| CodecDeserializeError::new("JSON", e))?)
+    }
+}
+
+/// Which `Codec` a database uses, set with `Options::codec`.
+/// Defaults to bincode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    #[cfg(feature = "postcard")]
+    Postcard,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl CodecKind {
+    /// what the file header records for this codec.
+    /// these never change, whichever features are enabled
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            CodecKind::Bincode => 0,
+            #[cfg(feature = "postcard")]
+            CodecKind::Postcard => 1,
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => 2,
+            #[cfg(feature = "json")]
+            CodecKind::Json => 3,
+        }
+    }
+
+    /// the length of what `encode` returns
+    pub(crate) fn encoded_len<T: Serialize + ?Sized>(self, t: &T) -> crate::Result<u64> {
+        match self {
+            CodecKind::Bincode => Ok(bincode::serialized_size(t).map_err(bincode_serialize_error)?),
+            #[allow(unreachable_patterns)]
+            _ => self.encode(t).map(|encoded| encoded.len() as u64),
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize + ?Sized>(&self, t: &T) -> crate::Result<Vec<u8>> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(t),
+            #[cfg(feature = "postcard")]
+            CodecKind::Postcard => Postcard.encode(t),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => MessagePack.encode(t),
+            #[cfg(feature = "json")]
+            CodecKind::Json => Json.encode(t),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            #[cfg(feature = "postcard")]
+            CodecKind::Postcard => Postcard.decode(bytes),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => MessagePack.decode(bytes),
+            #[cfg(feature = "json")]
+            CodecKind::Json => Json.decode(bytes),
+        }
+    }
+}
+
+fn bincode_serialize_error(source: Box<bincode::ErrorKind>) -> SerializeError {
+    SerializeError {
+        msg: "unable to serialize to bincode".to_string(),
+        source,
+    }
+}
@@ -0,0 +1,100 @@
+/// How values are compressed, set with `Options::compression`.
+///
+/// Values smaller than `Options::compression_threshold` are stored as they are,
+/// as are values that compression would not make any smaller.
+/// Each record says what its value is compressed with,
+/// so changing this only changes how new records are written,
+/// and merging rewrites the records it copies to match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd, at the given level, from 1 to 22. zstd's own default is 3
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// lz4, which is faster than zstd, and compresses less
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// Compress a serialized value, if it is big enough and compression makes it smaller.
+    /// Returns what the value ended up compressed with.
+    pub(crate) fn compress(
+        self,
+        value: Vec<u8>,
+        threshold: usize,
+    ) -> crate::Result<(CompressionKind, Vec<u8>)> {
+        if value.len() < threshold {
+            return Ok((CompressionKind::None, value));
+        }
+
+        match self.compressed(&value)? {
+            Some(compressed) if compressed.len() < value.len() => Ok((self.kind(), compressed)),
+            _ => Ok((CompressionKind::None, value)),
+        }
+    }
+
+    pub(crate) fn kind(self) -> CompressionKind {
+        match self {
+            Compression::None => CompressionKind::None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => CompressionKind::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => CompressionKind::Lz4,
+        }
+    }
+
+    /// `None` if there is no compression
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    fn compressed(self, value: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        match self {
+            Compression::None => Ok(None),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Ok(Some(zstd::bulk::compress(value, level)?)),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(Some(lz4_flex::compress_prepend_size(value))),
+        }
+    }
+}
+
+/// What a single value is compressed with,
+/// whichever compression features are enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum CompressionKind {
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl TryFrom<u8> for CompressionKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CompressionKind::None),
+            1 => Ok(CompressionKind::Zstd),
+            2 => Ok(CompressionKind::Lz4),
+            other => Err(other),
+        }
+    }
+}
+
+impl CompressionKind {
+    pub(crate) fn decompress(self, value: Vec<u8>) -> crate::Result<Vec<u8>> {
+        match self {
+            CompressionKind::None => Ok(value),
+            #[cfg(feature = "zstd")]
+            CompressionKind::Zstd => zstd::stream::decode_all(value.as_slice())
+                .map_err(|e| crate::error::Error::DecompressError(e.to_string())),
+            #[cfg(not(feature = "zstd"))]
+            CompressionKind::Zstd => Err(crate::error::Error::CompressionNotEnabled("zstd")),
+            #[cfg(feature = "lz4")]
+            CompressionKind::Lz4 => lz4_flex::decompress_size_prepended(&value)
+                .map_err(|e| crate::error::Error::DecompressError(e.to_string())),
+            #[cfg(not(feature = "lz4"))]
+            CompressionKind::Lz4 => Err(crate::error::Error::CompressionNotEnabled("lz4")),
+        }
+    }
+}
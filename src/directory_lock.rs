@@ -0,0 +1,37 @@
+use std::fs::TryLockError;
+use std::path::Path;
+
+/// An exclusive advisory lock (`flock`) on a `LOCK` file in the database directory,
+/// so that only one `B2` at a time, in this process or any other,
+/// writes to the directory.
+///
+/// The lock is released when this is dropped, or when the process exits.
+#[derive(Debug)]
+pub(crate) struct DirectoryLock(
+    // only held for the lock
+    #[allow(dead_code)] std::fs::File,
+);
+
+impl DirectoryLock {
+    const FILE_NAME: &'static str = "LOCK";
+
+    /// returns `Error::DatabaseLocked` if something else holds the lock
+    pub(crate) async fn acquire(db_directory: &Path) -> crate::Result<Self> {
+        let file = tokio::fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(db_directory.join(Self::FILE_NAME))
+            .await?
+            .into_std()
+            .await;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self(file)),
+            Err(TryLockError::WouldBlock) => {
+                Err(crate::error::Error::DatabaseLocked(db_directory.to_owned()))
+            }
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::codec::{Codec, CodecKind};
+#[cfg(feature = "encryption")]
+use crate::encryption::Cipher;
+use crate::file_header::FileHeader;
+use crate::record::{SizeFields, ValueHeader};
+use crate::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// How keys and values become the bytes stored in records, and back:
+/// serialized with the codec, then encrypted, if there is an encryption key.
+/// (Values are compressed in between, by `Compression`.)
+/// Also how wide the size fields of the records that hold them are,
+/// and which version of the data file format those records are in.
+///
+/// Deletes and batch commits are not encrypted, as their values
+/// are always the same, and say nothing about what was stored.
+///
+/// A value is bound to its record's key, as stored, and `TxId`,
+/// so it fails to decrypt if it is moved into any other record.
+#[derive(Clone, Debug)]
+pub(crate) struct Encoding {
+    pub(crate) codec: CodecKind,
+    pub(crate) size_fields: SizeFields,
+    /// the current version, unless this is `for_file` an older data file
+    pub(crate) format_version: u16,
+    #[cfg(feature = "encryption")]
+    cipher: Option<Cipher>,
+}
+
+impl Encoding {
+    const KEY_AAD: &'static [u8] = b"key";
+    const VALUE_AAD: &'static [u8] = b"value";
+
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            codec: options.codec,
+            size_fields: options.size_fields,
+            format_version: FileHeader::CURRENT_VERSION,
+            #[cfg(feature = "encryption")]
+            cipher: options.encryption_key.as_ref().map(Cipher::new),
+        }
+    }
+
+    /// the encoding of the records in the data file with the given header,
+    /// which must already have been `check`ed against this encoding
+    pub(crate) fn for_file(&self, header: &FileHeader) -> Self {
+        Self {
+            format_version: header.version,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        if self.cipher.is_some() {
+            return true;
+        }
+
+        false
+    }
+
+    /// encrypt a key that has already been serialized
+    pub(crate) fn seal_key(&self, key: Vec<u8>) -> Vec<u8> {
+        self.seal(key, Self::KEY_AAD)
+    }
+
+    pub(crate) fn decode_key<K: DeserializeOwned>(&self, stored_key: &[u8]) -> crate::Result<K> {
+        let encoded_key = self.open_key(stored_key)?;
+        self.codec.decode(&encoded_key)
+    }
+
+    /// a key as it was serialized, before it was encrypted
+    pub(crate) fn open_key<'a>(
+        &self,
+        stored_key: &'a [u8],
+    ) -> crate::Result<std::borrow::Cow<'a, [u8]>> {
+        self.open(stored_key, Self::KEY_AAD)
+    }
+
+    /// the length of a key once it is serialized and sealed
+    pub(crate) fn encoded_key_len<K: Serialize + ?Sized>(&self, k: &K) -> crate::Result<u64> {
+        Ok(self.sealed_len(self.codec.encoded_len(k)?))
+    }
+
+    /// the length of a key or value of the given length once it is sealed
+    pub(crate) fn sealed_len(&self, len: u64) -> u64 {
+        #[cfg(feature = "encryption")]
+        if self.cipher.is_some() {
+            return len + Cipher::OVERHEAD as u64;
+        }
+
+        len
+    }
+
+    /// encrypt a value that has already been serialized and compressed,
+    /// for the record with the given key, as stored, and header
+    pub(crate) fn seal_value(
+        &self,
+        value: Vec<u8>,
+        stored_key: &[u8],
+        header: ValueHeader,
+    ) -> Vec<u8> {
+        if !self.is_encrypted() {
+            return value;
+        }
+
+        self.seal(value, &Self::value_aad(stored_key, header))
+    }
+
+    pub(crate) fn open_value(
+        &self,
+        stored_value: Vec<u8>,
+        stored_key: &[u8],
+        header: ValueHeader,
+    ) -> crate::Result<Vec<u8>> {
+        if !self.is_encrypted() {
+            return Ok(stored_value);
+        }
+
+        Ok(self
+            .open(&stored_value, &Self::value_aad(stored_key, header))?
+            .into_owned())
+    }
+
+    fn value_aad(stored_key: &[u8], header: ValueHeader) -> Vec<u8> {
+        let mut aad = Self::VALUE_AAD.to_vec();
+        aad.extend_from_slice(&header.to_bytes());
+        aad.extend_from_slice(stored_key);
+        aad
+    }
+
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn seal(&self, bytes: Vec<u8>, aad: &[u8]) -> Vec<u8> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.seal(&bytes, aad);
+        }
+
+        bytes
+    }
+
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn open<'a>(&self, bytes: &'a [u8], aad: &[u8]) -> crate::Result<std::borrow::Cow<'a, [u8]>> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.open(bytes, aad).map(std::borrow::Cow::Owned);
+        }
+
+        Ok(std::borrow::Cow::Borrowed(bytes))
+    }
+}
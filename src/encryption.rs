@@ -0,0 +1,91 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+/// A 256-bit key that data files are encrypted with, set with `Options::encryption_key`.
+///
+/// Keep it somewhere other than the database directory:
+/// without it, the database can't be read at all.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// a new random key
+    pub fn generate() -> Self {
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Encrypts and authenticates the key and value bytes of records with XChaCha20-Poly1305.
+///
+/// Each encrypted field is a random nonce, followed by the ciphertext and its tag,
+/// so it is `Cipher::OVERHEAD` bytes longer than the plaintext.
+#[derive(Clone)]
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+impl Cipher {
+    const NONCE_SIZE: usize = 24;
+    const TAG_SIZE: usize = 16;
+    pub(crate) const OVERHEAD: usize = Self::NONCE_SIZE + Self::TAG_SIZE;
+
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        Self(XChaCha20Poly1305::new(&key.0.into()))
+    }
+
+    /// `aad` is authenticated along with the plaintext,
+    /// so a field can't be moved to where a different `aad` is expected
+    pub(crate) fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("records are far smaller than what the cipher can encrypt");
+
+        let mut sealed = Vec::with_capacity(Self::NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// returns `Error::DecryptionFailed` if the key is wrong,
+    /// or the field was changed after it was sealed
+    pub(crate) fn open(&self, sealed: &[u8], aad: &[u8]) -> crate::Result<Vec<u8>> {
+        if sealed.len() < Self::OVERHEAD {
+            return Err(crate::error::Error::DecryptionFailed);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(Self::NONCE_SIZE);
+
+        self.0
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| crate::error::Error::DecryptionFailed)
+    }
+}
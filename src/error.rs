@@ -10,10 +10,103 @@ pub enum Error {
     SerializeError(#[from] SerializeError),
     #[error("could not deserialize")]
     DeserializeError(#[from] DeserializeError),
+    #[error("could not serialize")]
+    CodecSerializeError(#[from] CodecSerializeError),
+    #[error("could not deserialize")]
+    CodecDeserializeError(#[from] CodecDeserializeError),
     #[error("could not parse")]
     ParseIntError(#[from] ParseIntError),
     #[error("hash from input and computed hash do not match")]
     CorruptRecord,
+    #[error("merge was cancelled")]
+    MergeCancelled,
+    #[error("{0:?} is not an inactive data file, so it cannot be merged")]
+    NotAnInactiveFile(crate::FileId),
+    #[error("the manifest of an interrupted merge could not be read")]
+    CorruptMergeManifest,
+    #[error("the write was interrupted before it finished, and may or may not have been written")]
+    WriteInterrupted,
+    #[error("the database in {0:?} is already open, in this process or another")]
+    DatabaseLocked(std::path::PathBuf),
+    #[error("the database is open read-only")]
+    ReadOnly,
+    #[error("the database is partway through committing a merge, so it cannot be opened read-only until the merge is finished")]
+    MergeNotFinished,
+    #[error("{0:?} is not a b2 data file")]
+    NotADataFile(std::path::PathBuf),
+    #[error("{0:?} is in data file format version {1}, which this version of b2 can't read")]
+    UnsupportedFormatVersion(std::path::PathBuf, u16),
+    #[error("{0:?} was written with a different codec than `Options::codec`")]
+    CodecMismatch(std::path::PathBuf),
+    #[error("the batch was built with a different codec than `Options::codec`")]
+    BatchCodecMismatch,
+    #[error("a value could not be decompressed: {0}")]
+    DecompressError(String),
+    #[error("a value is compressed with {0}, which needs the `{0}` cargo feature")]
+    CompressionNotEnabled(&'static str),
+    #[error("a record could not be decrypted: the encryption key is wrong, or the record was tampered with")]
+    DecryptionFailed,
+    #[error(
+        "{0:?} is encrypted and `Options::encryption_key` is not set, or the other way around"
+    )]
+    EncryptionMismatch(std::path::PathBuf),
+    #[error("{0:?} was written with different size fields than `Options::size_fields`")]
+    SizeFieldsMismatch(std::path::PathBuf),
+    #[error("the key is {size} bytes as stored, and the limit is {limit}")]
+    KeyTooLarge { size: u64, limit: u64 },
+    #[error("the value is {size} bytes as stored, and the limit is {limit}")]
+    ValueTooLarge { size: u64, limit: u64 },
+    #[error("the record was moved by a merge in the writing process, and this database has yet to catch up with it")]
+    RecordMoved,
+}
+
+impl Error {
+    /// an error like this one, for when one error has to be reported to many callers,
+    /// as when a group of writes fails together
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Error::IoError(e) => Error::IoError(std::io::Error::new(e.kind(), e.to_string())),
+            Error::CorruptRecord => Error::CorruptRecord,
+            Error::MergeCancelled => Error::MergeCancelled,
+            Error::NotAnInactiveFile(file_id) => Error::NotAnInactiveFile(*file_id),
+            Error::CorruptMergeManifest => Error::CorruptMergeManifest,
+            Error::WriteInterrupted => Error::WriteInterrupted,
+            Error::DatabaseLocked(db_directory) => Error::DatabaseLocked(db_directory.clone()),
+            Error::ReadOnly => Error::ReadOnly,
+            Error::MergeNotFinished => Error::MergeNotFinished,
+            Error::NotADataFile(path) => Error::NotADataFile(path.clone()),
+            Error::UnsupportedFormatVersion(path, version) => {
+                Error::UnsupportedFormatVersion(path.clone(), *version)
+            }
+            Error::CodecMismatch(path) => Error::CodecMismatch(path.clone()),
+            Error::BatchCodecMismatch => Error::BatchCodecMismatch,
+            Error::DecompressError(msg) => Error::DecompressError(msg.clone()),
+            Error::CompressionNotEnabled(feature) => Error::CompressionNotEnabled(feature),
+            Error::DecryptionFailed => Error::DecryptionFailed,
+            Error::EncryptionMismatch(path) => Error::EncryptionMismatch(path.clone()),
+            Error::SizeFieldsMismatch(path) => Error::SizeFieldsMismatch(path.clone()),
+            Error::KeyTooLarge { size, limit } => Error::KeyTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            Error::ValueTooLarge { size, limit } => Error::ValueTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            Error::RecordMoved => Error::RecordMoved,
+            Error::CodecSerializeError(e) => Error::CodecSerializeError(CodecSerializeError {
+                msg: e.msg.clone(),
+                source: e.source.to_string().into(),
+            }),
+            Error::CodecDeserializeError(e) => {
+                Error::CodecDeserializeError(CodecDeserializeError {
+                    msg: e.msg.clone(),
+                    source: e.source.to_string().into(),
+                })
+            }
+            e => Error::IoError(std::io::Error::other(e.to_string())),
+        }
+    }
 }
 
 /// a wrapper because bincode errors do not differentiate
@@ -45,3 +138,57 @@ impl std::fmt::Display for DeserializeError {
         write!(formatter, "{}: {}", self.msg, self.source)
     }
 }
+
+/// like `SerializeError`, for the codecs other than bincode
+#[derive(Debug, Error)]
+pub struct CodecSerializeError {
+    pub msg: String,
+    #[source]
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+#[cfg(any(feature = "postcard", feature = "msgpack", feature = "json"))]
+impl CodecSerializeError {
+    pub(crate) fn new(
+        codec_name: &str,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            msg: format!("unable to serialize to {codec_name}"),
+            source: source.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for CodecSerializeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "{}: {}", self.msg, self.source)
+    }
+}
+
+/// like `DeserializeError`, for the codecs other than bincode
+#[derive(Debug, Error)]
+pub struct CodecDeserializeError {
+    pub msg: String,
+    #[source]
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+#[cfg(any(feature = "postcard", feature = "msgpack", feature = "json"))]
+impl CodecDeserializeError {
+    pub(crate) fn new(
+        codec_name: &str,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            msg: format!("unable to deserialize from {codec_name}"),
+            source: source.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for CodecDeserializeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "{}: {}", self.msg, self.source)
+    }
+}
@@ -0,0 +1,206 @@
+use crate::codec::CodecKind;
+use crate::encoding::Encoding;
+use crate::record::Record;
+use crate::record::SizeFields;
+use crate::record::Timestamp;
+use crate::Options;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
+
+/// The first bytes of every data file, which identify it as a b2 data file
+/// and say how to read the records that follow.
+///
+/// - magic (4 bytes, `b2db`)
+/// - format version (2 bytes)
+/// - codec (1 byte), what keys and values are serialized with
+/// - flags (1 byte): the `CompressionKind` new values were compressed with
+///   when the file was created in the low 4 bits, whether its records have
+///   `SizeFields::Wide` size fields in bit 6, and whether it is encrypted in the high bit
+/// - created_at (8 bytes), in milliseconds since the unix epoch
+///
+/// Records start right after the header, so the first record
+/// of every data file is at offset `FileHeader::SIZE`,
+/// except in data files written before there were headers.
+/// Those are read as version 0, and their records start at offset 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileHeader {
+    pub(crate) version: u16,
+    /// the `CodecKind::id` of the codec the file's keys and values are serialized with
+    pub(crate) codec: u8,
+    /// the compression part is only informational,
+    /// as each record says how its own value is compressed
+    pub(crate) flags: u8,
+    pub(crate) created_at: Timestamp,
+}
+
+impl FileHeader {
+    pub(crate) const SIZE: u64 = 16;
+    pub(crate) const CURRENT_VERSION: u16 = 2;
+    /// versions 0 and 1 are still read, and merge rewrites them as the current version
+    pub(crate) const OLDEST_SUPPORTED_VERSION: u16 = Self::LEGACY_VERSION;
+    /// the version of data files with no header,
+    /// whose keys and values are serialized with bincode, with compact size fields
+    pub(crate) const LEGACY_VERSION: u16 = 0;
+    /// the first version with deletes flagged in the record header,
+    /// rather than marked by a magic value. See `Record::legacy_tombstone`
+    pub(crate) const FLAGGED_DELETES_VERSION: u16 = 2;
+    const MAGIC: [u8; 4] = *b"b2db";
+    const ENCRYPTED: u8 = 0x80;
+    const WIDE_SIZE_FIELDS: u8 = 0x40;
+
+    /// the header for a data file created now
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            version: Self::CURRENT_VERSION,
+            codec: options.codec.id(),
+            flags: options.compression.kind() as u8
+                | if Encoding::new(options).is_encrypted() {
+                    Self::ENCRYPTED
+                } else {
+                    0
+                }
+                | match options.size_fields {
+                    SizeFields::Compact => 0,
+                    SizeFields::Wide => Self::WIDE_SIZE_FIELDS,
+                },
+            created_at: Timestamp::now(),
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::SIZE as usize] {
+        let mut buf = [0u8; Self::SIZE as usize];
+        buf[0..4].copy_from_slice(&Self::MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6] = self.codec;
+        buf[7] = self.flags;
+        buf[8..16].copy_from_slice(&self.created_at.to_be_bytes());
+        buf
+    }
+
+    /// Write a new header to the start of a newly created data file, and flush it,
+    /// so that a data file on disk always starts with a whole header.
+    pub(crate) async fn write_new(
+        file: &mut tokio::fs::File,
+        options: &Options,
+    ) -> crate::Result<()> {
+        file.write_all(&Self::new(options).to_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// returns `Error::CodecMismatch` if the file at `path` was not written with the given codec,
+    /// `Error::EncryptionMismatch` if it is encrypted and the encoding is not, or the other way around,
+    /// and `Error::SizeFieldsMismatch` if its records have different size fields
+    pub(crate) fn check(&self, encoding: &Encoding, path: &Path) -> crate::Result<()> {
+        if self.codec != encoding.codec.id() {
+            return Err(crate::error::Error::CodecMismatch(path.to_owned()));
+        }
+
+        if self.is_encrypted() != encoding.is_encrypted() {
+            return Err(crate::error::Error::EncryptionMismatch(path.to_owned()));
+        }
+
+        if self.size_fields() != encoding.size_fields {
+            return Err(crate::error::Error::SizeFieldsMismatch(path.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn size_fields(&self) -> SizeFields {
+        if self.flags & Self::WIDE_SIZE_FIELDS != 0 {
+            SizeFields::Wide
+        } else {
+            SizeFields::Compact
+        }
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.flags & Self::ENCRYPTED != 0
+    }
+
+    /// where the first record of the data file is
+    pub(crate) fn records_start(&self) -> u64 {
+        if self.version == Self::LEGACY_VERSION {
+            0
+        } else {
+            Self::SIZE
+        }
+    }
+
+    /// Read and check the header of the data file at `path`.
+    ///
+    /// Returns `Ok(None)` if the file is empty,
+    /// `Error::NotADataFile` if it neither starts with the magic bytes
+    /// nor is a version 0 data file,
+    /// and `Error::UnsupportedFormatVersion` if it is in a format this version can't read.
+    /// A header that is cut short is an `UnexpectedEof` io error, like a torn record.
+    /// Either way, the reader is left at the first record.
+    pub(crate) async fn read_from<R: AsyncRead + AsyncSeek + Unpin>(
+        reader: &mut tokio::io::BufReader<R>,
+        path: &Path,
+    ) -> crate::Result<Option<Self>> {
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut buf = Vec::with_capacity(Self::SIZE as usize);
+
+        (&mut *reader)
+            .take(Self::SIZE)
+            .read_to_end(&mut buf)
+            .await?;
+
+        // so that a short file that is not a data file at all
+        // is not mistaken for a data file with a torn header
+        let magic_len = buf.len().min(Self::MAGIC.len());
+
+        if buf[..magic_len] != Self::MAGIC[..magic_len] {
+            return Self::read_legacy(reader, path).await;
+        }
+
+        if buf.len() as u64 != Self::SIZE {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let version = u16::from_be_bytes(buf[4..6].try_into().unwrap());
+
+        if !(Self::OLDEST_SUPPORTED_VERSION..=Self::CURRENT_VERSION).contains(&version) {
+            return Err(crate::error::Error::UnsupportedFormatVersion(
+                path.to_owned(),
+                version,
+            ));
+        }
+
+        Ok(Some(Self {
+            version,
+            codec: buf[6],
+            flags: buf[7],
+            created_at: u64::from_be_bytes(buf[8..16].try_into().unwrap()).into(),
+        }))
+    }
+
+    /// A data file with no header is taken to be a version 0 data file
+    /// if it starts with a whole, valid record in that version's layout,
+    /// so that a file that is not a data file at all is not mistaken for one.
+    async fn read_legacy<R: AsyncRead + AsyncSeek + Unpin>(
+        reader: &mut tokio::io::BufReader<R>,
+        path: &Path,
+    ) -> crate::Result<Option<Self>> {
+        reader.seek(std::io::SeekFrom::Start(0)).await?;
+
+        match Record::read_from(reader, SizeFields::Compact, Self::LEGACY_VERSION).await {
+            Ok(Some(record)) if record.is_valid() => (),
+            _ => return Err(crate::error::Error::NotADataFile(path.to_owned())),
+        }
+
+        reader.seek(std::io::SeekFrom::Start(0)).await?;
+
+        Ok(Some(Self {
+            version: Self::LEGACY_VERSION,
+            codec: CodecKind::Bincode.id(),
+            flags: 0,
+            created_at: 0.into(),
+        }))
+    }
+}
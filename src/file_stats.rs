@@ -0,0 +1,73 @@
+use crate::keydir::FileId;
+use std::collections::HashMap;
+
+/// How much of a data file is still in use.
+///
+/// A record is dead once a later insert or delete of its key supersedes it,
+/// and merging the file reclaims its dead bytes.
+/// The records that mark the end of a batch are dead as soon as they are written.
+/// Deletes are never counted as dead, as a merge cannot always drop them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStats {
+    pub file_id: FileId,
+    /// the size of the data file, in bytes
+    pub total_bytes: u64,
+    /// the bytes of the data file that a merge would reclaim
+    pub dead_bytes: u64,
+}
+
+impl FileStats {
+    pub fn live_bytes(&self) -> u64 {
+        self.total_bytes - self.dead_bytes
+    }
+
+    /// dead bytes as a fraction of total bytes, from 0.0 to 1.0
+    pub fn dead_bytes_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// the `FileStats` of every data file,
+/// kept up to date by the writer and by merge
+#[derive(Debug, Default)]
+pub(crate) struct FileStatsTable(HashMap<FileId, FileStats>);
+
+impl FileStatsTable {
+    /// `len` more bytes were appended to the given file
+    pub(crate) fn written(&mut self, file_id: FileId, len: u64) {
+        self.entry(file_id).total_bytes += len;
+    }
+
+    /// `len` bytes of the given file were superseded
+    pub(crate) fn superseded(&mut self, file_id: FileId, len: u64) {
+        let stats = self.entry(file_id);
+        stats.dead_bytes = (stats.dead_bytes + len).min(stats.total_bytes);
+    }
+
+    pub(crate) fn remove(&mut self, file_id: FileId) {
+        self.0.remove(&file_id);
+    }
+
+    pub(crate) fn get(&self, file_id: FileId) -> Option<&FileStats> {
+        self.0.get(&file_id)
+    }
+
+    /// in `FileId` order
+    pub(crate) fn to_vec(&self) -> Vec<FileStats> {
+        let mut all: Vec<FileStats> = self.0.values().copied().collect();
+        all.sort_by_key(|stats| stats.file_id);
+        all
+    }
+
+    fn entry(&mut self, file_id: FileId) -> &mut FileStats {
+        self.0.entry(file_id).or_insert(FileStats {
+            file_id,
+            total_bytes: 0,
+            dead_bytes: 0,
+        })
+    }
+}
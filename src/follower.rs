@@ -0,0 +1,202 @@
+use crate::encoding::Encoding;
+use crate::file_header::FileHeader;
+use crate::keydir::{EntryWithLiveness, FileId};
+use crate::loadable::{Loadable, Loaded, PendingBatch};
+use crate::recovery::{DroppedRange, RecoveryReport};
+use crate::RecoveryMode;
+use serde::de::DeserializeOwned;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncSeekExt;
+
+/// How far a follower has read the data files that another process is writing.
+///
+/// The writer only ever appends to its newest data file (the tail),
+/// and only ever changes an older data file by merging it.
+/// So a follower reads the tail from where it left off, and any newer files in full,
+/// and if the `MergeGeneration` has moved on since it last loaded everything,
+/// files it has already read have been merged, and it loads everything again.
+#[derive(Debug, Default)]
+pub(crate) struct FollowState {
+    /// the `MergeGeneration` when everything was last loaded
+    merge_generation: u64,
+    /// the newest data file, which the writer may still be appending to.
+    /// `None` until something has been loaded
+    tail_file_id: Option<FileId>,
+    /// how much of the tail has been read
+    tail_offset: u64,
+}
+
+impl FollowState {
+    /// Returns `false` if any data file that has been read has been merged, or removed,
+    /// in which case the follower has to load everything again.
+    /// `merge_generation` is the current `MergeGeneration`.
+    pub(crate) async fn is_current(
+        &self,
+        db_directory: &Path,
+        db_file_ids: &[FileId],
+        merge_generation: u64,
+    ) -> crate::Result<bool> {
+        let Some(tail_file_id) = self.tail_file_id else {
+            return Ok(db_file_ids.is_empty());
+        };
+
+        if merge_generation != self.merge_generation {
+            return Ok(false);
+        }
+
+        match tokio::fs::metadata(data_path(db_directory, tail_file_id)).await {
+            Ok(metadata) => Ok(metadata.len() >= self.tail_offset),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Start over, with every file older than the tail read in full,
+    /// and nothing read of the tail.
+    /// `merge_generation` is the `MergeGeneration` from before they were read.
+    pub(crate) fn reset(&mut self, tail_file_id: FileId, merge_generation: u64) {
+        self.merge_generation = merge_generation;
+        self.tail_file_id = Some(tail_file_id);
+        self.tail_offset = 0;
+    }
+
+    /// where to start reading the given file, which is the tail or newer than it
+    pub(crate) fn offset(&self, file_id: FileId) -> u64 {
+        if Some(file_id) == self.tail_file_id {
+            self.tail_offset
+        } else {
+            0
+        }
+    }
+
+    /// the given file is the newest, and has been read up to `offset`
+    pub(crate) fn read_tail(&mut self, file_id: FileId, offset: u64) {
+        self.tail_file_id = Some(file_id);
+        self.tail_offset = offset;
+    }
+
+    /// the tail, and every file newer than it, in `FileId` order
+    pub(crate) fn unread_file_ids(&self, db_file_ids: &[FileId]) -> Vec<FileId> {
+        db_file_ids
+            .iter()
+            .copied()
+            .filter(|file_id| self.tail_file_id.is_none_or(|tail| *file_id >= tail))
+            .collect()
+    }
+}
+
+fn data_path(db_directory: &Path, file_id: FileId) -> PathBuf {
+    db_directory.join(file_id.to_string())
+}
+
+/// What a follower read from a data file.
+pub(crate) struct Followed<K> {
+    /// every insert and delete that took effect, in the order they were written
+    pub(crate) entries: Vec<(K, EntryWithLiveness)>,
+    /// the end of the last complete record or committed batch
+    pub(crate) offset: u64,
+    /// bytes read that are dead as soon as they are written
+    pub(crate) dead_bytes: u64,
+}
+
+/// Reads the given data file from `offset` onward, up to the end of
+/// the last complete record or committed batch, as the writer may be
+/// partway through writing whatever follows it.
+/// The header is read and checked every time, as it says
+/// which version of the format the records are in.
+pub(crate) async fn read_from<K>(
+    db_directory: &Path,
+    file_id: FileId,
+    offset: u64,
+    encoding: &Encoding,
+    recovery_mode: RecoveryMode,
+    recovery_report: &mut RecoveryReport,
+) -> crate::Result<Followed<K>>
+where
+    K: Eq + Hash + DeserializeOwned,
+{
+    let path = data_path(db_directory, file_id);
+
+    let f = tokio::fs::File::open(&path).await?;
+
+    let mut reader = tokio::io::BufReader::new(f);
+
+    let mut followed = Followed {
+        entries: vec![],
+        offset,
+        dead_bytes: 0,
+    };
+
+    let (encoding, records_start) = match FileHeader::read_from(&mut reader, &path).await {
+        Ok(Some(header)) => {
+            header.check(encoding, &path)?;
+            (encoding.for_file(&header), header.records_start())
+        }
+        // the writer has only just created the file
+        Ok(None) => return Ok(followed),
+        Err(crate::error::Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Ok(followed)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut offset = offset.max(records_start);
+
+    followed.offset = offset;
+
+    if offset > records_start {
+        reader.seek(std::io::SeekFrom::Start(offset)).await?;
+    }
+
+    let mut pending_batch = PendingBatch::new();
+
+    loop {
+        let record_offset = offset;
+
+        match EntryWithLiveness::read_one(&mut reader, &mut offset, file_id, &encoding).await {
+            Ok(Some(Loaded::Entry(k, entry_with_liveness))) => {
+                pending_batch.drop_uncommitted(file_id, record_offset, recovery_report);
+                followed.entries.push((k, entry_with_liveness));
+                followed.offset = offset;
+            }
+            Ok(Some(Loaded::BatchEntry(k, entry_with_liveness))) => {
+                pending_batch.push(record_offset, (k, entry_with_liveness));
+            }
+            Ok(Some(Loaded::BatchCommit(len, _tx_id))) => {
+                if let Some(records) =
+                    pending_batch.commit_or_drop(len, file_id, offset, recovery_report)
+                {
+                    followed.entries.extend(records);
+                }
+                followed.dead_bytes += offset - record_offset;
+                followed.offset = offset;
+            }
+            // the writer has not written any more yet
+            Ok(None) => break,
+            Err(crate::error::Error::IoError(e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            Err(crate::error::Error::CorruptRecord) if recovery_mode != RecoveryMode::Strict => {
+                offset = reader.stream_position().await?;
+
+                recovery_report.dropped.push(DroppedRange {
+                    file_id,
+                    offset: record_offset,
+                    len: offset - record_offset,
+                    quarantined_to: None,
+                });
+
+                // it takes the batch it is part of with it
+                if pending_batch.is_empty() {
+                    followed.offset = offset;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(followed)
+}
@@ -0,0 +1,54 @@
+use crate::record::TxId;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+/// The largest `TxId` of any record that a merge has merged.
+///
+/// The writer gives out `TxId`s starting after the largest one in the data files,
+/// but a merge can drop the records with the largest `TxId`s:
+/// deletes, expired inserts, and the records that commit batches.
+/// So a merge raises the high-water mark to cover what it merged before
+/// it drops anything, and `TxId`s are never given out twice.
+///
+/// The high-water mark is a small text file holding the `TxId` in decimal.
+pub(crate) struct HighWaterMark;
+
+impl HighWaterMark {
+    const FILE_NAME: &'static str = "TX_ID";
+    const TEMP_FILE_NAME: &'static str = "TX_ID.tmp";
+
+    /// returns `Ok(None)` if nothing has been merged yet
+    pub(crate) async fn read(db_directory: &Path) -> crate::Result<Option<TxId>> {
+        let contents = match tokio::fs::read_to_string(db_directory.join(Self::FILE_NAME)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let tx_id: u128 = contents.trim().parse()?;
+
+        Ok(Some(tx_id.into()))
+    }
+
+    /// durably raise the high-water mark to `tx_id`, if it is not already that high
+    pub(crate) async fn raise(db_directory: &Path, tx_id: TxId) -> crate::Result<()> {
+        if Self::read(db_directory)
+            .await?
+            .is_some_and(|current| current >= tx_id)
+        {
+            return Ok(());
+        }
+
+        let temp_path = db_directory.join(Self::TEMP_FILE_NAME);
+
+        let mut f = tokio::fs::File::create(&temp_path).await?;
+
+        f.write_all(format!("{}\n", *tx_id).as_bytes()).await?;
+
+        f.sync_all().await?;
+
+        tokio::fs::rename(temp_path, db_directory.join(Self::FILE_NAME)).await?;
+
+        crate::base::sync_directory(db_directory).await
+    }
+}
@@ -0,0 +1,213 @@
+use crate::compression::CompressionKind;
+use crate::encoding::Encoding;
+use crate::keydir::{FileId, Liveness};
+use crate::record::{ExpiresAt, KeySize, Record, SizeFields, Timestamp, TxId, ValueSize};
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt};
+
+/// A hint is a small pointer to a record in a data file.
+/// Merge writes one hint per record it writes, to a hint file
+/// that sits next to the merged data file (`N` gets `N.hint`),
+/// so that opening the database can build the keydir
+/// without reading any values.
+///
+/// The layout is (in on-disk and in-memory order):
+/// - hash (4 bytes)
+/// - liveness (1 byte): 1 if deleted in the low 4 bits,
+///   and the `CompressionKind` of the value in the next 2, as in a record
+/// - tx_id (16 bytes)
+/// - timestamp (8 bytes)
+/// - expires_at (8 bytes)
+/// - key_size (2 bytes, or 4 with `SizeFields::Wide`)
+/// - value_size (4 bytes, or 8 with `SizeFields::Wide`)
+/// - value_position (8 bytes)
+/// - key
+pub(crate) struct Hint {
+    buf: Vec<u8>,
+    size_fields: SizeFields,
+}
+
+impl Deref for Hint {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+// crate-public impls
+impl Hint {
+    pub(crate) const FILE_EXTENSION: &'static str = "hint";
+
+    /// the hint file of the data file with the given id
+    pub(crate) fn path(db_directory: &Path, file_id: FileId) -> PathBuf {
+        db_directory.join(format!("{}.{}", *file_id, Self::FILE_EXTENSION))
+    }
+
+    /// the hint for a record whose value is at `value_position` in its data file
+    pub(crate) fn new(record: &Record, value_position: u64) -> Self {
+        let key_bytes = record.key_bytes();
+
+        let size_fields = record.size_fields();
+
+        let mut buf = Vec::with_capacity(Self::header_size(size_fields) + key_bytes.len());
+        // dummy hash bytes, added back in at the end...
+        buf.extend_from_slice(&[0u8; Self::HASH_SIZE]);
+        let liveness: u8 = match record.liveness() {
+            Liveness::Live => 0,
+            Liveness::Deleted => 1,
+        };
+        buf.push(liveness | (record.compression() as u8) << Self::COMPRESSION_SHIFT);
+        buf.extend_from_slice(&record.tx_id().to_be_bytes());
+        buf.extend_from_slice(&record.timestamp().to_be_bytes());
+        buf.extend_from_slice(&record.expires_at().to_be_bytes());
+        buf.extend_from_slice(&size_fields.encode_key_size(key_bytes.len()));
+        buf.extend_from_slice(&size_fields.encode_value_size(record.value_size().0 as usize));
+        buf.extend_from_slice(&value_position.to_be_bytes());
+        buf.extend_from_slice(key_bytes);
+
+        let hash = crc32fast::hash(&buf[Self::HASH_SIZE..]);
+        // ...and finally set the first HASH_SIZE bytes to the hash
+        buf[..Self::HASH_SIZE].copy_from_slice(&hash.to_be_bytes());
+
+        Hint { buf, size_fields }
+    }
+
+    /// returns `Ok(None)` if the reader is cleanly at the end of the file,
+    /// and an `UnexpectedEof` error if a hint is only partially present
+    pub(crate) async fn read_from<R: AsyncRead + Unpin>(
+        reader: &mut tokio::io::BufReader<R>,
+        size_fields: SizeFields,
+    ) -> std::io::Result<Option<Hint>> {
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
+
+        let header_size = Self::header_size(size_fields);
+
+        let mut hint = Hint {
+            buf: vec![0u8; header_size],
+            size_fields,
+        };
+
+        reader.read_exact(&mut hint.buf).await?;
+
+        let key_size = u64::from(hint.key_size().0);
+
+        // as with records, the key size of a torn or corrupt hint can be garbage
+        let bytes_read = (&mut *reader)
+            .take(key_size)
+            .read_to_end(&mut hint.buf)
+            .await?;
+
+        if bytes_read as u64 != key_size {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(Some(hint))
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        let hash = u32::from_be_bytes(self.buf[..Self::HASH_SIZE].try_into().unwrap());
+        let liveness_byte = self.buf[Self::HASH_SIZE];
+
+        hash == crc32fast::hash(&self.buf[Self::HASH_SIZE..])
+            && liveness_byte & Self::LIVENESS_MASK <= 1
+            && CompressionKind::try_from(liveness_byte >> Self::COMPRESSION_SHIFT).is_ok()
+    }
+
+    pub(crate) fn key<K: DeserializeOwned>(&self, encoding: &Encoding) -> crate::Result<K> {
+        encoding.decode_key(&self.buf[Self::header_size(self.size_fields)..])
+    }
+
+    /// merge only writes tombstones when it merges some, but not all, inactive files,
+    /// as older records for the deleted key may be in a file it did not merge
+    pub(crate) fn liveness(&self) -> Liveness {
+        if self.buf[Self::HASH_SIZE] & Self::LIVENESS_MASK == 0 {
+            Liveness::Live
+        } else {
+            Liveness::Deleted
+        }
+    }
+
+    /// only meaningful for valid hints
+    pub(crate) fn compression(&self) -> CompressionKind {
+        CompressionKind::try_from(self.buf[Self::HASH_SIZE] >> Self::COMPRESSION_SHIFT).unwrap()
+    }
+
+    pub(crate) fn tx_id(&self) -> TxId {
+        let start = Self::HASH_SIZE + Self::LIVENESS_SIZE;
+        let end = start + Self::TX_ID_SIZE;
+        u128::from_be_bytes(self.buf[start..end].try_into().unwrap()).into()
+    }
+
+    pub(crate) fn timestamp(&self) -> Timestamp {
+        let start = Self::HASH_SIZE + Self::LIVENESS_SIZE + Self::TX_ID_SIZE;
+        let end = start + Self::TIMESTAMP_SIZE;
+        u64::from_be_bytes(self.buf[start..end].try_into().unwrap()).into()
+    }
+
+    pub(crate) fn expires_at(&self) -> ExpiresAt {
+        let start = Self::HASH_SIZE + Self::LIVENESS_SIZE + Self::TX_ID_SIZE + Self::TIMESTAMP_SIZE;
+        let end = start + Self::EXPIRES_AT_SIZE;
+        u64::from_be_bytes(self.buf[start..end].try_into().unwrap()).into()
+    }
+
+    pub(crate) fn key_size(&self) -> KeySize {
+        let start = Self::HASH_SIZE
+            + Self::LIVENESS_SIZE
+            + Self::TX_ID_SIZE
+            + Self::TIMESTAMP_SIZE
+            + Self::EXPIRES_AT_SIZE;
+        let end = start + self.size_fields.key_size_size();
+        self.size_fields.decode_key_size(&self.buf[start..end])
+    }
+
+    pub(crate) fn value_size(&self) -> ValueSize {
+        let start = Self::HASH_SIZE
+            + Self::LIVENESS_SIZE
+            + Self::TX_ID_SIZE
+            + Self::TIMESTAMP_SIZE
+            + Self::EXPIRES_AT_SIZE
+            + self.size_fields.key_size_size();
+        let end = start + self.size_fields.value_size_size();
+        self.size_fields.decode_value_size(&self.buf[start..end])
+    }
+
+    pub(crate) fn value_position(&self) -> u64 {
+        let start = Self::HASH_SIZE
+            + Self::LIVENESS_SIZE
+            + Self::TX_ID_SIZE
+            + Self::TIMESTAMP_SIZE
+            + Self::EXPIRES_AT_SIZE
+            + self.size_fields.key_size_size()
+            + self.size_fields.value_size_size();
+        let end = start + Self::VALUE_POSITION_SIZE;
+        u64::from_be_bytes(self.buf[start..end].try_into().unwrap())
+    }
+}
+
+// private impls
+impl Hint {
+    const HASH_SIZE: usize = std::mem::size_of::<u32>();
+    const LIVENESS_SIZE: usize = 1;
+    const LIVENESS_MASK: u8 = 0x0f;
+    const COMPRESSION_SHIFT: u32 = 4;
+    const TX_ID_SIZE: usize = std::mem::size_of::<TxId>();
+    const TIMESTAMP_SIZE: usize = std::mem::size_of::<Timestamp>();
+    const EXPIRES_AT_SIZE: usize = std::mem::size_of::<ExpiresAt>();
+    const VALUE_POSITION_SIZE: usize = std::mem::size_of::<u64>();
+
+    fn header_size(size_fields: SizeFields) -> usize {
+        Self::HASH_SIZE
+            + Self::LIVENESS_SIZE
+            + Self::TX_ID_SIZE
+            + Self::TIMESTAMP_SIZE
+            + Self::EXPIRES_AT_SIZE
+            + size_fields.key_size_size()
+            + size_fields.value_size_size()
+            + Self::VALUE_POSITION_SIZE
+    }
+}
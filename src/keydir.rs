@@ -1,5 +1,7 @@
-use crate::loadable::Loadable;
-use crate::record::{TxId, ValueSize};
+use crate::compression::CompressionKind;
+use crate::encoding::Encoding;
+use crate::loadable::{Loadable, Loaded};
+use crate::record::{ExpiresAt, RecordKind, Timestamp, TxId, ValueSize};
 use serde::de::DeserializeOwned;
 use std::collections::HashMap;
 use std::hash::Hash;
@@ -29,19 +31,17 @@ where
         self.0.remove(k)
     }
 
+    /// false if the key's entry has expired
     pub(crate) fn contains_key(&self, k: &K) -> bool {
-        self.0.contains_key(k)
+        self.0.get(k).is_some_and(|entry| !entry.is_expired())
     }
 
-    pub(crate) fn keys(&self) -> std::collections::hash_map::Keys<'_, K, EntryPointer> {
-        self.0.keys()
-    }
-
-    pub(crate) fn latest_tx_id(&self) -> Option<TxId> {
+    /// every key whose entry has not expired
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
         self.0
-            .values()
-            .max_by(|a, b| a.tx_id.cmp(&b.tx_id))
-            .map(|entry| entry.tx_id)
+            .iter()
+            .filter(|(_k, entry)| !entry.is_expired())
+            .map(|(k, _entry)| k)
     }
 }
 
@@ -54,7 +54,7 @@ where
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct EntryPointer {
     /// the file that contains the data this pointer refers to
     pub(crate) file_id: FileId,
@@ -63,8 +63,25 @@ pub(crate) struct EntryPointer {
     pub(crate) value_position: u64,
     /// the size in bytes of the value field this pointer refers to
     pub(crate) value_size: ValueSize,
+    /// the size in bytes of the whole record, whose header depends on
+    /// the format version of the file it is in
+    pub(crate) record_size: u64,
+    /// what the value field is compressed with
+    pub(crate) compression: CompressionKind,
     /// the txid allows us to answer for two entries, "which happened first?"
     pub(crate) tx_id: TxId,
+    /// when the value was written
+    pub(crate) timestamp: Timestamp,
+    /// when the value stops being visible
+    pub(crate) expires_at: ExpiresAt,
+}
+
+impl EntryPointer {
+    /// an expired entry stays in the keydir until merge drops its record,
+    /// as it still shadows older records for its key
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at.has_passed()
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -93,53 +110,113 @@ where
         reader: &mut tokio::io::BufReader<R>,
         offset: &mut u64,
         file_id: FileId,
-    ) -> crate::Result<Option<(K, Self)>>
+        encoding: &Encoding,
+    ) -> crate::Result<Option<Loaded<K, Self>>>
     where
         Self: Sized,
     {
         // end header
-        let record = match crate::record::Record::read_from(reader).await {
-            Ok(record) => record,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    return Ok(None);
-                } else {
-                    return Err(e.into());
-                }
-            }
+        let record = match crate::record::Record::read_from(
+            reader,
+            encoding.size_fields,
+            encoding.format_version,
+        )
+        .await?
+        {
+            Some(record) => record,
+            None => return Ok(None),
         };
 
         if !record.is_valid() {
             return Err(crate::error::Error::CorruptRecord);
         }
 
-        let key = record.key()?;
+        if record.kind() == RecordKind::BatchCommit {
+            *offset += record.len() as u64;
+            return Ok(Some(Loaded::BatchCommit(
+                record.batch_len(),
+                record.tx_id(),
+            )));
+        }
+
+        let key = record.key(encoding)?;
 
         let liveness = record.liveness();
 
-        let value_position =
-            *offset + crate::record::Record::HEADER_SIZE as u64 + record.key_size().0 as u64;
+        let value_position = *offset + record.value_offset();
 
         // and update the offset to reflect that we have read a record
         *offset += record.len() as u64;
 
-        Ok(Some((
+        Ok(Some(Loaded::new(
+            record.kind(),
             key,
             EntryWithLiveness {
                 liveness,
                 entry: EntryPointer {
                     file_id,
                     value_size: record.value_size(),
+                    record_size: record.len() as u64,
+                    compression: record.compression(),
                     value_position,
                     tx_id: record.tx_id(),
+                    timestamp: record.timestamp(),
+                    expires_at: record.expires_at(),
+                },
+            },
+        )))
+    }
+
+    async fn read_one_hint<R: AsyncRead + Unpin>(
+        reader: &mut tokio::io::BufReader<R>,
+        file_id: FileId,
+        encoding: &Encoding,
+    ) -> crate::Result<Option<(K, Self)>>
+    where
+        Self: Sized,
+    {
+        let hint = match crate::hint::Hint::read_from(reader, encoding.size_fields).await? {
+            Some(hint) => hint,
+            None => return Ok(None),
+        };
+
+        if !hint.is_valid() {
+            return Err(crate::error::Error::CorruptRecord);
+        }
+
+        let key = hint.key(encoding)?;
+
+        Ok(Some((
+            key,
+            EntryWithLiveness {
+                liveness: hint.liveness(),
+                entry: EntryPointer {
+                    file_id,
+                    value_size: hint.value_size(),
+                    record_size: crate::record::Record::len_for(
+                        encoding.size_fields,
+                        encoding.format_version,
+                        hint.key_size().0.into(),
+                        &hint.value_size(),
+                    ),
+                    compression: hint.compression(),
+                    value_position: hint.value_position(),
+                    tx_id: hint.tx_id(),
+                    timestamp: hint.timestamp(),
+                    expires_at: hint.expires_at(),
                 },
             },
         )))
     }
+
+    fn tx_id(&self) -> TxId {
+        self.entry.tx_id
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub(crate) struct FileId(u32);
+/// Identifies a data file. Data files are named after their `FileId`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(u32);
 
 impl FromStr for FileId {
     type Err = ParseIntError;
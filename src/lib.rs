@@ -1,17 +1,26 @@
 #![forbid(unsafe_code)]
-//! todo:
-//! workout the locking scheme for:
+//! the locking scheme is:
 //! - "regular read" operations (get, etc)
 //! - "regular write" operations (insert, remove)
-//! - "special write" operations (flush, merge)
+//! - "special write" operations (merge)
 //!
-//! my thinking right now is that regular read and regular write operations
-//! can happen concurrently. they should not block each other at all
+//! regular read and regular write operations happen concurrently.
+//! they do not block each other, with one exception:
+//! reading a value that is still in the write buffer
+//! has to wait for the writer to flush it.
+//! regular write operations queue up for the active file,
+//! and whichever of them gets it writes everything that is queued
+//! with a single flush or sync (group commit).
 //!
-//! special write operations should be exclusive.
+//! special write operations are exclusive.
 //! they block both regular read *and* regular write operations.
+//! merge does most of its work as a regular read operation,
+//! and is only a special write operation while it swaps in the merged files.
 
-use crate::base::Base;
+use crate::base::{Base, OpenMode};
+use crate::codec::CodecKind;
+use crate::merge::{MergeControl, MergeSelection};
+use crate::record::ExpiresAt;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fmt::Debug;
@@ -20,12 +29,44 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+mod active_file;
 mod base;
+mod batch;
+pub mod codec;
+mod compression;
+mod directory_lock;
+mod encoding;
+#[cfg(feature = "encryption")]
+mod encryption;
 pub mod error;
+mod file_header;
+mod file_stats;
+mod follower;
+mod high_water_mark;
+mod hint;
 mod keydir;
 mod loadable;
+mod merge;
+mod merge_generation;
+mod merge_manifest;
 mod merge_pointer;
+mod metadata;
 mod record;
+mod recovery;
+mod verify;
+mod write_queue;
+
+pub use batch::WriteBatch;
+pub use compression::Compression;
+#[cfg(feature = "encryption")]
+pub use encryption::EncryptionKey;
+pub use file_stats::FileStats;
+pub use keydir::FileId;
+pub use merge::{MergeHandle, MergePolicy, MergeProgress, MergeWindow};
+pub use metadata::Metadata;
+pub use record::SizeFields;
+pub use recovery::{DroppedRange, RecoveryReport};
+pub use verify::{verify, verify_with, FileReport, VerifyReport};
 
 pub type Result<T> = std::result::Result<T, error::Error>;
 
@@ -39,9 +80,64 @@ pub struct Options {
     pub max_concurrent_readers: u32,
     /// when to flush the database's in-memory write buffer
     pub flush_behavior: FlushBehavior,
+    /// when to make flushed writes durable, with `fdatasync`
+    pub sync_behavior: SyncBehavior,
     /// the size of the internal write buffer, in bytes.
     /// defaults to 8kB.
     pub write_buffer_size: usize,
+    /// what to do with invalid records found when loading data files
+    pub recovery_mode: RecoveryMode,
+    /// when to merge without being asked to.
+    /// defaults to `None`, meaning only when `merge` is called
+    pub merge_policy: Option<MergePolicy>,
+    /// how often a database opened with `open_follower` checks for new writes.
+    /// defaults to 1 second
+    pub follow_interval: std::time::Duration,
+    /// what keys and values are serialized with.
+    /// every data file must have been written with the same codec.
+    /// defaults to bincode
+    pub codec: CodecKind,
+    /// what values are compressed with.
+    /// defaults to `Compression::None`
+    pub compression: Compression,
+    /// values smaller than this many bytes (once serialized) are never compressed.
+    /// defaults to 512
+    pub compression_threshold: usize,
+    /// if set, the keys and values in data and hint files are encrypted with this key.
+    /// every data file must have been written with the same key, or none at all.
+    /// defaults to `None`
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<EncryptionKey>,
+    /// how wide the key size and value size fields of records are.
+    /// every data file must have been written with the same.
+    /// defaults to `SizeFields::Compact`
+    pub size_fields: SizeFields,
+    /// inserts and removes with larger keys, as stored
+    /// (serialized, and encrypted if there is an encryption key),
+    /// fail with `Error::KeyTooLarge`.
+    /// defaults to `None`, the largest key `size_fields` can hold
+    pub max_key_size: Option<u64>,
+    /// inserts with larger values, as stored
+    /// (serialized, compressed, and encrypted if there is an encryption key),
+    /// fail with `Error::ValueTooLarge`.
+    /// defaults to `None`, the largest value `size_fields` can hold
+    pub max_value_size: Option<u64>,
+}
+
+impl Options {
+    /// the size of the largest key that can be written, as stored,
+    /// which is `max_key_size` if it is set and `size_fields` can hold it
+    pub fn key_size_limit(&self) -> u64 {
+        let max = self.size_fields.max_key_size();
+        self.max_key_size.map_or(max, |limit| limit.min(max))
+    }
+
+    /// the size of the largest value that can be written, as stored,
+    /// which is `max_value_size` if it is set and `size_fields` can hold it
+    pub fn value_size_limit(&self) -> u64 {
+        let max = self.size_fields.max_value_size();
+        self.max_value_size.map_or(max, |limit| limit.min(max))
+    }
 }
 
 impl Default for Options {
@@ -50,7 +146,19 @@ impl Default for Options {
             max_file_size_bytes: 2u64.pow(28),
             max_concurrent_readers: 1024,
             flush_behavior: FlushBehavior::default(),
+            sync_behavior: SyncBehavior::default(),
             write_buffer_size: 8 * 1024,
+            recovery_mode: RecoveryMode::default(),
+            merge_policy: None,
+            follow_interval: std::time::Duration::from_secs(1),
+            codec: CodecKind::default(),
+            compression: Compression::default(),
+            compression_threshold: 512,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            size_fields: SizeFields::default(),
+            max_key_size: None,
+            max_value_size: None,
         }
     }
 }
@@ -66,6 +174,10 @@ impl Default for Options {
 /// `WhenFull` means the buffer is flushed to disk when full.
 /// This offers higher throughput at the expense of durability.
 ///
+/// `Interval` means the buffer is flushed to disk when full,
+/// and also by a background task on a timer,
+/// which bounds how long a write can sit in the buffer.
+///
 #[derive(Clone, Debug, Default, PartialEq)]
 pub enum FlushBehavior {
     /// flush the internal write buffer to disk on every single `insert` and `remove`
@@ -76,15 +188,92 @@ pub enum FlushBehavior {
     /// call `flush` manually if read-after-write is imporant to you
     /// when using this option
     WhenFull,
+    /// flush the internal write buffer when it is full,
+    /// and at least this often
+    Interval(std::time::Duration),
 }
 
-#[derive(Clone, Debug)]
+/// Governs when writes are synced to disk (with `fdatasync`),
+/// so that they survive the machine losing power or crashing,
+/// and not just the process dying.
+///
+/// Flushing (see `FlushBehavior`) only hands writes to the operating system.
+/// A sync always flushes first.
+/// Whatever the setting, the directory is synced whenever a data file is created,
+/// and unless the setting is `Never`, a data file is synced when it stops being the active file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum SyncBehavior {
+    /// never sync, and leave it to the operating system
+    /// to write flushed data to disk when it sees fit
+    #[default]
+    Never,
+    /// sync after every single `insert`, `remove` and `write_batch`.
+    /// this is the only setting that guarantees that a write
+    /// is durable once it returns, and it is also the slowest
+    AfterEveryWrite,
+    /// sync at least this often, if anything has been written since the last sync.
+    /// a background task does the syncing, so writes never wait on it,
+    /// other than while it holds the active file
+    Interval(std::time::Duration),
+    /// sync after a write once at least this many bytes have been written since the last sync
+    Bytes(u64),
+}
+
+/// Governs what happens when loading a data file turns up
+/// a record that is torn (only partially written, as happens
+/// when the process dies in the middle of a write)
+/// or corrupt (its hash does not match its contents).
+///
+/// Whatever is dropped is listed in the database's `RecoveryReport`.
+///
+/// A merge never changes the files it merges, so with `TruncateTail`
+/// it fails with `Error::CorruptRecord` rather than truncating one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// fail to open the database if any record is torn or corrupt
+    Strict,
+    /// stop reading a data file at its first torn or corrupt record,
+    /// moving everything from that record onward into a `.corrupt` file
+    /// next to the data file, and truncating the data file.
+    /// note that any valid records that follow a corrupt record
+    /// in the same file end up in the `.corrupt` file as well.
+    #[default]
+    TruncateTail,
+    /// skip over corrupt records and ignore torn records,
+    /// without changing any files on disk
+    SkipCorrupt,
+}
+
+#[derive(Debug)]
 pub struct B2<K>
 where
     K: Eq + Hash + Serialize + DeserializeOwned + Send,
 {
     db_directory: PathBuf,
+    /// `Options::codec`, for `new_batch`
+    codec: CodecKind,
     base: Arc<RwLock<Base<K>>>,
+    /// only one merge runs at a time
+    merge_lock: Arc<tokio::sync::Mutex<()>>,
+    /// the tasks that run `Options::merge_policy`, `FlushBehavior::Interval`,
+    /// `SyncBehavior::Interval`, and `open_follower`
+    background_tasks: Arc<std::sync::Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+}
+
+// not derived, as that would require `K: Clone`
+impl<K> Clone for B2<K>
+where
+    K: Eq + Hash + Serialize + DeserializeOwned + Send,
+{
+    fn clone(&self) -> Self {
+        Self {
+            db_directory: self.db_directory.clone(),
+            codec: self.codec,
+            base: Arc::clone(&self.base),
+            merge_lock: Arc::clone(&self.merge_lock),
+            background_tasks: Arc::clone(&self.background_tasks),
+        }
+    }
 }
 
 impl<K> B2<K>
@@ -92,18 +281,69 @@ where
     K: Eq + Hash + Serialize + DeserializeOwned + Send,
 {
     /// Opens the database in the given directory, creating it if it does not exist.
-    pub async fn open(db_directory: &Path, options: Options) -> Result<Self> {
-        assert!(options.max_file_size_bytes > 0);
+    pub async fn open(db_directory: &Path, options: Options) -> Result<Self>
+    where
+        // for the tasks that run `Options::merge_policy`,
+        // `FlushBehavior::Interval` and `SyncBehavior::Interval`
+        K: Sync + 'static,
+    {
+        let db = Self::open_with_mode(db_directory, options.clone(), OpenMode::ReadWrite).await?;
+
+        if let Some(merge_policy) = options.merge_policy {
+            db.spawn_merge_policy(merge_policy);
+        }
 
-        let base = Arc::new(RwLock::with_max_readers(
-            Base::new(db_directory, options.clone()).await?,
-            options.max_concurrent_readers,
-        ));
+        if let FlushBehavior::Interval(interval) = options.flush_behavior {
+            db.spawn_interval_flush(interval);
+        }
 
-        Ok(Self {
-            db_directory: db_directory.to_owned(),
-            base,
-        })
+        if let SyncBehavior::Interval(interval) = options.sync_behavior {
+            db.spawn_interval_sync(interval);
+        }
+
+        Ok(db)
+    }
+
+    /// Opens the database in the given directory without changing anything in it,
+    /// for inspecting a database, or a copy of one, safely.
+    ///
+    /// This does not make a new active file or take the directory lock,
+    /// so it can be opened while another process has the database open.
+    /// It does not see anything written after it was opened, though,
+    /// and once the other process merges, `get` returns `Error::RecordMoved`
+    /// for the records that were moved. Use `open_follower` to keep up with a writer.
+    /// `insert`, `remove`, `write_batch` and every kind of merge
+    /// return `Error::ReadOnly`, and `Options::merge_policy`,
+    /// `FlushBehavior::Interval` and `SyncBehavior::Interval` are ignored.
+    /// Torn or corrupt records are skipped over rather than truncated,
+    /// unless `Options::recovery_mode` is `RecoveryMode::Strict`.
+    /// Returns `Error::MergeNotFinished` if a merge is partway through being committed.
+    pub async fn open_read_only(db_directory: &Path, options: Options) -> Result<Self> {
+        Self::open_with_mode(db_directory, options, OpenMode::ReadOnly).await
+    }
+
+    /// Opens the database in the given directory read-only, like `open_read_only`,
+    /// and keeps it up to date with whatever another process writes to it,
+    /// checking every `Options::follow_interval`, or whenever `catch_up` is called.
+    ///
+    /// Writes that are still in the writer's write buffer can't be seen,
+    /// so the follower is only as up to date as the writer's `FlushBehavior` allows.
+    /// When the writer merges, the follower loads the whole database again.
+    /// Until then, `get` checks every record it reads, and catches up first
+    /// if the record has been merged away.
+    /// It returns `Error::RecordMoved` if the writer is still swapping merged files in.
+    pub async fn open_follower(db_directory: &Path, options: Options) -> Result<Self>
+    where
+        // for the task that checks for new writes
+        K: Sync + 'static,
+    {
+        let follow_interval = options.follow_interval;
+
+        let db = Self::open_with_mode(db_directory, options, OpenMode::Follower).await?;
+
+        db.spawn_follow(follow_interval);
+
+        Ok(db)
     }
 
     /// Get the value for a given key, if it exists.
@@ -113,15 +353,60 @@ where
     }
 
     /// Insert the the given key and value, overwriting any previous values.
-    pub async fn insert<V: Serialize + DeserializeOwned + Send>(&self, k: K, v: V) -> Result<()> {
-        let mut base = self.base.write().await;
-        base.insert(k, v).await
+    pub async fn insert<V: Serialize + DeserializeOwned + Send>(&self, k: K, v: V) -> Result<()>
+    where
+        // for the task that writes, see `Base::write`
+        K: Sync + 'static,
+    {
+        let base = Arc::clone(&self.base).read_owned().await;
+        let write = base.prepare_insert(k, v, ExpiresAt::NEVER)?;
+        Base::write(base, write).await
+    }
+
+    /// Insert the given key and value, overwriting any previous values,
+    /// such that the key is treated as absent once `ttl` has passed.
+    /// An expired key's record is dropped the next time its file is merged.
+    pub async fn insert_with_ttl<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        k: K,
+        v: V,
+        ttl: std::time::Duration,
+    ) -> Result<()>
+    where
+        K: Sync + 'static,
+    {
+        let base = Arc::clone(&self.base).read_owned().await;
+        let write = base.prepare_insert(k, v, ExpiresAt::after(ttl))?;
+        Base::write(base, write).await
     }
 
     /// Delete a given key and value.
-    pub async fn remove(&self, k: K) -> Result<()> {
-        let mut base = self.base.write().await;
-        base.remove(k).await
+    pub async fn remove(&self, k: K) -> Result<()>
+    where
+        K: Sync + 'static,
+    {
+        let base = Arc::clone(&self.base).read_owned().await;
+        let write = base.prepare_remove(k)?;
+        Base::write(base, write).await
+    }
+
+    /// An empty batch, serializing with the database's codec.
+    pub fn new_batch(&self) -> WriteBatch<K> {
+        WriteBatch::with_codec(self.codec)
+    }
+
+    /// Apply all of the inserts and removes in the batch, or none of them.
+    /// Returns `Error::BatchCodecMismatch` if the batch was built with a different codec
+    /// than the database's, as `WriteBatch::new` batches are for databases that don't use bincode.
+    pub async fn write_batch(&self, batch: WriteBatch<K>) -> Result<()>
+    where
+        K: Sync + 'static,
+    {
+        let base = Arc::clone(&self.base).read_owned().await;
+        match base.prepare_batch(batch)? {
+            Some(write) => Base::write(base, write).await,
+            None => Ok(()),
+        }
     }
 
     /// Returns true if the database has any non-delete entry for the given key.
@@ -130,24 +415,278 @@ where
         base.contains_key(k)
     }
 
+    /// When the given key's current value was written, and where it is stored,
+    /// if the key exists.
+    pub async fn metadata(&self, k: &K) -> Option<Metadata> {
+        let base = self.base.read().await;
+        base.metadata(k)
+    }
+
     /// Merge database files so only the most recent writes exist.
+    ///
+    /// Reads and writes carry on while the merged files are written,
+    /// and are only blocked briefly while the merged files are swapped in.
     pub async fn merge(&self) -> Result<()> {
-        let mut base = self.base.write().await;
-        base.merge().await
+        self.merge_with_control(&MergeControl::default(), MergeSelection::All)
+            .await
+    }
+
+    /// Merge only the given inactive data files, leaving every other file as it is.
+    /// `file_stats` shows which files have the most to reclaim.
+    ///
+    /// Unlike `merge`, this keeps the deletes it finds, unless the given files
+    /// are all of the inactive files, as the records they delete may be in other files.
+    /// Returns `Error::NotAnInactiveFile` if any of the files is the active file
+    /// or does not exist.
+    pub async fn merge_files(&self, file_ids: &[FileId]) -> Result<()> {
+        self.merge_with_control(&MergeControl::default(), MergeSelection::Files(file_ids))
+            .await
+    }
+
+    /// Merge only the inactive data files that cross the given policy's thresholds,
+    /// right now, regardless of the policy's time windows.
+    /// This is what the background task started by `Options::merge_policy` does.
+    pub async fn merge_by_policy(&self, merge_policy: &MergePolicy) -> Result<()> {
+        self.merge_with_control(
+            &MergeControl::default(),
+            MergeSelection::Policy(merge_policy),
+        )
+        .await
     }
 
     /// provided to allow a manual flush of the internal write buffer.
     /// this is normally governed by the `FlushBehavior` setting on `Options`.
     pub async fn flush(&self) -> Result<()> {
-        let mut base = self.base.write().await;
+        let base = self.base.read().await;
         base.flush().await
     }
 
+    /// flush the internal write buffer and sync the active file to disk,
+    /// so that everything written so far survives a power loss.
+    /// this is normally governed by the `SyncBehavior` setting on `Options`.
+    pub async fn sync(&self) -> Result<()> {
+        let base = self.base.read().await;
+        base.sync().await
+    }
+
+    /// Flush and sync everything written so far, returning any error,
+    /// once any running merge has finished.
+    ///
+    /// This stops the background tasks started by `Options::merge_policy`,
+    /// `FlushBehavior::Interval`, `SyncBehavior::Interval` and `open_follower`,
+    /// so that once the last clone
+    /// of the database is closed, the database's directory lock is released
+    /// by the time this returns, and the database can be opened again.
+    ///
+    /// Dropping the database without closing it flushes it too,
+    /// but that ignores errors, and it blocks the thread it happens on.
+    /// Other clones of the database can still be used,
+    /// though what they write is only flushed when they are closed or dropped.
+    pub async fn close(self) -> Result<()> {
+        let _merging = self.merge_lock.lock().await;
+
+        // no merge is running, so stopping the background tasks cannot interrupt one
+        let background_tasks = std::mem::take(&mut *self.background_tasks.lock().unwrap());
+
+        for task in background_tasks {
+            task.abort();
+            let _ = task.await;
+        }
+
+        let base = self.base.write().await;
+        base.sync().await
+    }
+
+    /// If the database was opened with `open_follower`,
+    /// read whatever has been written to it since it last checked.
+    /// Otherwise, this does nothing.
+    pub async fn catch_up(&self) -> Result<()> {
+        let base = self.base.read().await;
+        base.catch_up().await
+    }
+
+    /// What was dropped when loading data files, according to `Options::recovery_mode`.
+    pub async fn recovery_report(&self) -> RecoveryReport {
+        let base = self.base.read().await;
+        base.recovery_report()
+    }
+
+    /// How much of each data file is dead, and would be reclaimed by merging it,
+    /// in `FileId` order.
+    pub async fn file_stats(&self) -> Vec<FileStats> {
+        let base = self.base.read().await;
+        base.file_stats()
+    }
+
     pub fn db_directory(&self) -> &Path {
         &self.db_directory
     }
 }
 
+// private impls
+impl<K> B2<K>
+where
+    K: Eq + Hash + Serialize + DeserializeOwned + Send,
+{
+    async fn open_with_mode(
+        db_directory: &Path,
+        options: Options,
+        open_mode: OpenMode,
+    ) -> Result<Self> {
+        assert!(options.max_file_size_bytes > 0);
+
+        let base = Arc::new(RwLock::with_max_readers(
+            Base::new(db_directory, options.clone(), open_mode).await?,
+            options.max_concurrent_readers,
+        ));
+
+        Ok(Self {
+            db_directory: db_directory.to_owned(),
+            codec: options.codec,
+            base,
+            merge_lock: Arc::new(tokio::sync::Mutex::new(())),
+            background_tasks: Arc::new(std::sync::Mutex::new(vec![])),
+        })
+    }
+
+    async fn merge_with_control(
+        &self,
+        control: &MergeControl,
+        selection: MergeSelection<'_>,
+    ) -> Result<()> {
+        let _merging = self.merge_lock.lock().await;
+
+        let outcome = {
+            let base = self.base.read().await;
+            base.write_merge_files(control, selection).await?
+        };
+
+        if outcome.is_empty() {
+            return Ok(());
+        }
+
+        let mut base = self.base.write().await;
+        base.commit_merge(outcome).await
+    }
+}
+
+impl<K> B2<K>
+where
+    K: Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Start a merge in a background task, returning a handle that can be
+    /// awaited, cancelled, or asked for progress.
+    /// See `merge`.
+    pub fn merge_in_background(&self) -> MergeHandle {
+        let db = self.clone();
+
+        let control = Arc::new(MergeControl::default());
+
+        let task_control = Arc::clone(&control);
+
+        let task = tokio::spawn(async move {
+            db.merge_with_control(&task_control, MergeSelection::All)
+                .await
+        });
+
+        MergeHandle::new(task, control)
+    }
+
+    /// flush every `interval` until the database is dropped
+    fn spawn_interval_flush(&self, interval: std::time::Duration) {
+        self.spawn_periodic(interval, |db| async move {
+            // a flush that fails is tried again at the next tick,
+            // and by every write that fills the buffer
+            let _ = db.flush().await;
+        });
+    }
+
+    /// sync every `interval`, if there is anything to sync, until the database is dropped
+    fn spawn_interval_sync(&self, interval: std::time::Duration) {
+        self.spawn_periodic(interval, |db| async move {
+            let base = db.base.read().await;
+
+            // as with flushing, a sync that fails is tried again at the next tick
+            let _ = base.sync_unsynced().await;
+        });
+    }
+
+    /// check for new writes every `interval` until the database is dropped
+    fn spawn_follow(&self, interval: std::time::Duration) {
+        self.spawn_periodic(interval, |db| async move {
+            // the writer may be partway through changing files,
+            // so a catch up that fails is tried again at the next tick
+            let _ = db.catch_up().await;
+        });
+    }
+
+    /// check the merge policy every `check_interval` until the database is dropped
+    fn spawn_merge_policy(&self, merge_policy: MergePolicy) {
+        self.spawn_periodic(merge_policy.check_interval, move |db| {
+            let merge_policy = merge_policy.clone();
+
+            async move {
+                if !merge_policy.allows(std::time::SystemTime::now()) {
+                    return;
+                }
+
+                // a merge that fails leaves the database as it was,
+                // so there is nothing to do but try again at the next check
+                let _ = db
+                    .merge_with_control(
+                        &MergeControl::default(),
+                        MergeSelection::Policy(&merge_policy),
+                    )
+                    .await;
+            }
+        });
+    }
+
+    /// Run `tick` every `interval`, starting one `interval` from now,
+    /// until the database is dropped or closed.
+    /// `tick` is given the database for as long as it runs,
+    /// and the task does not keep the database open in between.
+    fn spawn_periodic<F, Fut>(&self, interval: std::time::Duration, mut tick: F)
+    where
+        F: FnMut(Self) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let db_directory = self.db_directory.clone();
+        let codec = self.codec;
+        let base = Arc::downgrade(&self.base);
+        let merge_lock = Arc::clone(&self.merge_lock);
+        let background_tasks = Arc::clone(&self.background_tasks);
+
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            // the first tick completes immediately
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                let Some(base) = base.upgrade() else {
+                    break;
+                };
+
+                tick(Self {
+                    db_directory: db_directory.clone(),
+                    codec,
+                    base,
+                    merge_lock: Arc::clone(&merge_lock),
+                    background_tasks: Arc::clone(&background_tasks),
+                })
+                .await;
+            }
+        });
+
+        self.background_tasks.lock().unwrap().push(task);
+    }
+}
+
 impl<K> B2<K>
 where
     K: Clone + Eq + Hash + DeserializeOwned + Serialize + Send,
@@ -155,34 +694,17 @@ where
     /// Return a list of all keys that have live (non-deleted) values.
     pub async fn keys(&self) -> Vec<K> {
         let base = self.base.read().await;
-        base.keys().cloned().collect()
+        base.keys()
     }
 }
 
-// impl<
-//         K: Eq + Hash + Serialize + DeserializeOwned + Send,
-//         V: Serialize + DeserializeOwned + Send,
-//     > Drop for B2<K, V>
-// {
-//     fn drop(&mut self) {
-//         std::thread::scope(|s| {
-//             s.spawn(|| {
-//                 let rt = tokio::runtime::Builder::new_current_thread()
-//                     .build()
-//                     .unwrap();
-//                 rt.block_on(async {
-//                     // TODO is sync_all/sync_data necessary here?
-//                     let mut base = self.base.write().await;
-//                     let _ = base.flush().await.unwrap();
-//                 });
-//             });
-//         });
-//     }
-// }
-
 #[cfg(test)]
+// some of the original tests are written this way
+#[allow(clippy::clone_on_copy, clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::codec::Codec;
+    use crate::file_header::FileHeader;
 
     #[tokio::test]
     async fn simple_roundtrip() {
@@ -404,13 +926,2426 @@ mod tests {
         assert_eq!(get_files(&dir.path()).await.len(), 1);
     }
 
+    #[tokio::test]
+    async fn merge_writes_hint_files_that_are_used_on_open() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        for i in 0..10 {
+            db.insert(format!("key {i}"), i).await.unwrap();
+            db.insert(format!("key {i}"), i * 10).await.unwrap();
+        }
+
+        db.remove("key 0".to_string()).await.unwrap();
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.merge().await.unwrap();
+
+        let data_files = get_files(&dir.path()).await;
+
+        let hint_files: Vec<PathBuf> = data_files
+            .iter()
+            .map(|path| path.with_extension("hint"))
+            .filter(|path| path.exists())
+            .collect();
+
+        assert_eq!(hint_files.len(), 1);
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert!(!db.contains_key(&"key 0".to_string()).await);
+
+        for i in 1..10 {
+            let v: i32 = db.get(&format!("key {i}")).await.unwrap().unwrap();
+            assert_eq!(v, i * 10);
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_hint_file_falls_back_to_data_file() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.merge().await.unwrap();
+
+        drop(db);
+
+        let hint_file = get_files(&dir.path())
+            .await
+            .into_iter()
+            .map(|path| path.with_extension("hint"))
+            .find(|path| path.exists())
+            .unwrap();
+
+        let mut hint_bytes = tokio::fs::read(&hint_file).await.unwrap();
+        let last = hint_bytes.len() - 1;
+        hint_bytes[last] ^= 0xff;
+        tokio::fs::write(&hint_file, hint_bytes).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let v: String = db.get(&"foo".to_string()).await.unwrap().unwrap();
+
+        assert_eq!(v, "bar");
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_truncated_and_quarantined() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        drop(db);
+
+        let data_file = dir.path().join("1");
+
+        let good_len = tokio::fs::metadata(&data_file).await.unwrap().len();
+
+        // simulate a crash partway through writing a record
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        let torn = bytes[..10].to_vec();
+        bytes.extend_from_slice(&torn);
+        tokio::fs::write(&data_file, bytes).await.unwrap();
+
+        let strict_options = Options {
+            recovery_mode: RecoveryMode::Strict,
+            ..Default::default()
+        };
+
+        assert!(B2::<String>::open(dir.path(), strict_options)
+            .await
+            .is_err());
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let v: String = db.get(&"foo".to_string()).await.unwrap().unwrap();
+        assert_eq!(v, "bar");
+
+        let report = db.recovery_report().await;
+
+        assert_eq!(
+            report.dropped,
+            vec![DroppedRange {
+                file_id: 1.into(),
+                offset: good_len,
+                len: 10,
+                quarantined_to: Some(dir.path().join("1.corrupt")),
+            }]
+        );
+
+        assert_eq!(
+            tokio::fs::metadata(&data_file).await.unwrap().len(),
+            good_len
+        );
+
+        assert_eq!(
+            tokio::fs::read(dir.path().join("1.corrupt")).await.unwrap(),
+            torn
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_never_truncates_its_inputs() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            max_file_size_bytes: 100,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        for n in 0..10 {
+            db.insert(format!("key {n}"), format!("value {n}"))
+                .await
+                .unwrap();
+        }
+
+        // corrupted after the database loaded it
+        let data_file = dir.path().join("1");
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        assert!(matches!(db.merge().await, Err(error::Error::CorruptRecord)));
+
+        assert_eq!(
+            tokio::fs::metadata(&data_file).await.unwrap().len(),
+            len as u64
+        );
+        assert!(!dir.path().join("1.corrupt").exists());
+        assert!(db.recovery_report().await.is_clean());
+
+        assert_eq!(
+            db.get::<String>(&"key 9".to_string()).await.unwrap(),
+            Some("value 9".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_corrupt_skips_bad_records_without_changing_files() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), "1".to_string()).await.unwrap();
+
+        let data_file = dir.path().join("1");
+
+        let first_record_len =
+            tokio::fs::metadata(&data_file).await.unwrap().len() - FileHeader::SIZE;
+
+        db.insert("b".to_string(), "2".to_string()).await.unwrap();
+        db.insert("c".to_string(), "3".to_string()).await.unwrap();
+
+        drop(db);
+
+        // flip the last byte of the second record's value
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        let second_record_last_byte = (FileHeader::SIZE + first_record_len * 2 - 1) as usize;
+        bytes[second_record_last_byte] ^= 0xff;
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        let options = Options {
+            recovery_mode: RecoveryMode::SkipCorrupt,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        assert_eq!(
+            db.get::<String>(&"a".to_string()).await.unwrap().unwrap(),
+            "1"
+        );
+        assert!(!db.contains_key(&"b".to_string()).await);
+        assert_eq!(
+            db.get::<String>(&"c".to_string()).await.unwrap().unwrap(),
+            "3"
+        );
+
+        let report = db.recovery_report().await;
+
+        assert_eq!(
+            report.dropped,
+            vec![DroppedRange {
+                file_id: 1.into(),
+                offset: FileHeader::SIZE + first_record_len,
+                len: first_record_len,
+                quarantined_to: None,
+            }]
+        );
+
+        assert_eq!(tokio::fs::read(&data_file).await.unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn write_batch_applies_everything() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("old".to_string(), 1u32).await.unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.insert("a".to_string(), 2u32).unwrap();
+        batch.insert("b".to_string(), "three".to_string()).unwrap();
+        batch.remove("old".to_string()).unwrap();
+
+        db.write_batch(batch).await.unwrap();
+
+        assert_eq!(db.get::<u32>(&"a".to_string()).await.unwrap(), Some(2));
+        assert!(!db.contains_key(&"old".to_string()).await);
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<u32>(&"a".to_string()).await.unwrap(), Some(2));
+        assert_eq!(
+            db.get::<String>(&"b".to_string()).await.unwrap(),
+            Some("three".to_string())
+        );
+        assert!(!db.contains_key(&"old".to_string()).await);
+
+        db.merge().await.unwrap();
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<u32>(&"a".to_string()).await.unwrap(), Some(2));
+        assert!(!db.contains_key(&"old".to_string()).await);
+
+        let report = verify(dir.path()).await.unwrap();
+        assert!(report.is_ok());
+    }
+
+    #[tokio::test]
+    async fn torn_batch_is_not_applied() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), 1u32).await.unwrap();
+
+        let data_file = dir.path().join("1");
+
+        let before_batch_len = tokio::fs::metadata(&data_file).await.unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        batch.insert("a".to_string(), 2u32).unwrap();
+        batch.insert("b".to_string(), 3u32).unwrap();
+
+        db.write_batch(batch).await.unwrap();
+
+        drop(db);
+
+        // lose the end of the batch's commit record
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        bytes.truncate(bytes.len() - 2);
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<u32>(&"a".to_string()).await.unwrap(), Some(1));
+        assert!(!db.contains_key(&"b".to_string()).await);
+
+        let report = db.recovery_report().await;
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].offset, before_batch_len);
+        assert_eq!(
+            tokio::fs::metadata(&data_file).await.unwrap().len(),
+            before_batch_len
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_that_were_never_committed_are_in_the_recovery_report() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), 1u32).await.unwrap();
+
+        let data_file = dir.path().join("1");
+
+        let before_batch_len = tokio::fs::metadata(&data_file).await.unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        batch.insert("a".to_string(), 2u32).unwrap();
+        batch.insert("b".to_string(), 3u32).unwrap();
+
+        db.write_batch(batch).await.unwrap();
+
+        drop(db);
+
+        // lose the batch's commit, and follow the batch with a record of its own
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        bytes.truncate(bytes.len() - (record::Record::header_size(SizeFields::Compact) + 4));
+        let batch_end = bytes.len() as u64;
+        let first_record = bytes[FileHeader::SIZE as usize..before_batch_len as usize].to_vec();
+        bytes.extend_from_slice(&first_record);
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<u32>(&"a".to_string()).await.unwrap(), Some(1));
+        assert!(!db.contains_key(&"b".to_string()).await);
+
+        let report = db.recovery_report().await;
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].offset, before_batch_len);
+        assert_eq!(report.dropped[0].len, batch_end - before_batch_len);
+        assert_eq!(report.dropped[0].quarantined_to, None);
+    }
+
+    #[tokio::test]
+    async fn reads_see_writes_that_are_still_in_the_write_buffer() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            flush_behavior: FlushBehavior::WhenFull,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        let v: String = db.get(&"foo".to_string()).await.unwrap().unwrap();
+
+        assert_eq!(v, "bar");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_reads_and_writes() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            max_file_size_bytes: 1024,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        for i in 0..10 {
+            db.insert(format!("key {i}"), 0u32).await.unwrap();
+        }
+
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for i in 0..10 {
+            let writer = db.clone();
+            tasks.spawn(async move {
+                for round in 1..=20u32 {
+                    writer.insert(format!("key {i}"), round).await.unwrap();
+                }
+            });
+
+            let reader = db.clone();
+            tasks.spawn(async move {
+                let mut last_seen = 0;
+                for _ in 0..20 {
+                    let v: u32 = reader.get(&format!("key {i}")).await.unwrap().unwrap();
+                    // each key only ever moves forward
+                    assert!(v >= last_seen);
+                    last_seen = v;
+                }
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap();
+        }
+
+        for i in 0..10 {
+            let v: u32 = db.get(&format!("key {i}")).await.unwrap().unwrap();
+            assert_eq!(v, 20);
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_in_background_while_writing() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        for i in 0..10 {
+            db.insert(format!("k{i}"), i).await.unwrap();
+            db.insert(format!("k{i}"), i * 10).await.unwrap();
+        }
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let handle = db.merge_in_background();
+
+        // writes made while the merge runs win over what it merges
+        db.insert("k0".to_string(), 1000).await.unwrap();
+        db.remove("k1".to_string()).await.unwrap();
+
+        handle.await.unwrap();
+
+        assert_eq!(db.get::<i32>(&"k0".to_string()).await.unwrap(), Some(1000));
+        assert_eq!(db.get::<i32>(&"k1".to_string()).await.unwrap(), None);
+        assert_eq!(db.get::<i32>(&"k2".to_string()).await.unwrap(), Some(20));
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<i32>(&"k0".to_string()).await.unwrap(), Some(1000));
+        assert_eq!(db.get::<i32>(&"k1".to_string()).await.unwrap(), None);
+        assert_eq!(db.get::<i32>(&"k9".to_string()).await.unwrap(), Some(90));
+    }
+
+    #[tokio::test]
+    async fn merge_in_background_reports_progress() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        for i in 0..5 {
+            db.insert(format!("k{i}"), i).await.unwrap();
+        }
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let handle = db.merge_in_background();
+
+        assert_eq!(handle.progress(), MergeProgress::default());
+
+        while !handle.is_finished() {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(
+            handle.progress(),
+            MergeProgress {
+                keys_total: 5,
+                keys_processed: 5
+            }
+        );
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_merge_leaves_the_database_as_it_was() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        for i in 0..5 {
+            db.insert(format!("k{i}"), i).await.unwrap();
+            db.insert(format!("k{i}"), i + 1).await.unwrap();
+        }
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let files_before = get_files(&dir.path()).await;
+
+        let handle = db.merge_in_background();
+
+        handle.cancel();
+
+        assert!(matches!(handle.await, Err(error::Error::MergeCancelled)));
+
+        let mut files_after = get_files(&dir.path()).await;
+        let mut files_before = files_before;
+        files_before.sort();
+        files_after.sort();
+        assert_eq!(files_before, files_after);
+
+        let mut s = tokio::fs::read_dir(dir.path()).await.unwrap();
+        while let Some(e) = s.next_entry().await.unwrap() {
+            assert_ne!(e.path().extension().and_then(|e| e.to_str()), Some("merge"));
+        }
+
+        for i in 0..5 {
+            assert_eq!(db.get::<i32>(&format!("k{i}")).await.unwrap(), Some(i + 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn file_stats_count_superseded_records_as_dead() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), 1u64).await.unwrap();
+
+        let stats = db.file_stats().await;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].dead_bytes, 0);
+
+        let record_len = stats[0].total_bytes - FileHeader::SIZE;
+
+        db.insert("a".to_string(), 2u64).await.unwrap();
+        db.insert("b".to_string(), 3u64).await.unwrap();
+        db.remove("b".to_string()).await.unwrap();
+
+        let stats = db.file_stats().await;
+        // the first insert of each key is dead, the deletes are not
+        assert_eq!(stats[0].dead_bytes, 2 * record_len);
+
+        drop(db);
+
+        // the same accounting is rebuilt when opening the database
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let reopened_stats = db.file_stats().await;
+        assert_eq!(reopened_stats[..1], stats[..]);
+        // the new active file, which only has its header so far
+        assert_eq!(reopened_stats[1].total_bytes, FileHeader::SIZE);
+        assert_eq!(reopened_stats[1].dead_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn merge_files_leaves_other_files_alone() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        for file in 1..=3 {
+            let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+            for i in 0..3 {
+                db.insert(format!("file {file}"), i).await.unwrap();
+            }
+            drop(db);
+        }
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let file_1_before = tokio::fs::read(dir.path().join("1")).await.unwrap();
+        let file_2_before = tokio::fs::read(dir.path().join("2")).await.unwrap();
+        let file_3_before = tokio::fs::read(dir.path().join("3")).await.unwrap();
+
+        db.merge_files(&[FileId::from(2)]).await.unwrap();
+
+        let file_2_after = tokio::fs::read(dir.path().join("2")).await.unwrap();
+        let header_len = FileHeader::SIZE as usize;
+        assert_eq!(
+            file_2_after.len(),
+            (file_2_before.len() - header_len) / 3 + header_len
+        );
+
+        assert_eq!(
+            tokio::fs::read(dir.path().join("1")).await.unwrap(),
+            file_1_before
+        );
+        assert_eq!(
+            tokio::fs::read(dir.path().join("3")).await.unwrap(),
+            file_3_before
+        );
+
+        // the active file cannot be merged
+        assert!(matches!(
+            db.merge_files(&[FileId::from(4)]).await,
+            Err(error::Error::NotAnInactiveFile(file_id)) if *file_id == 4
+        ));
+
+        // not enough files cross the thresholds
+        db.merge_by_policy(&MergePolicy {
+            min_dead_bytes: 1,
+            min_files: 3,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            tokio::fs::read(dir.path().join("1")).await.unwrap(),
+            file_1_before
+        );
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        for file in 1..=3 {
+            assert_eq!(
+                db.get::<i32>(&format!("file {file}")).await.unwrap(),
+                Some(2)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn uncommitted_merge_is_rolled_back_on_open() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        for i in 0..3 {
+            db.insert("a".to_string(), i).await.unwrap();
+        }
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let file_1_before = tokio::fs::read(dir.path().join("1")).await.unwrap();
+
+        // the process dies after writing the merged files, but before committing them
+        {
+            let base = db.base.read().await;
+            base.write_merge_files(&MergeControl::default(), MergeSelection::All)
+                .await
+                .unwrap();
+        }
+
+        assert!(tokio::fs::try_exists(dir.path().join("1.merge"))
+            .await
+            .unwrap());
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert!(merge_manifest::merge_file_paths(dir.path())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            tokio::fs::read(dir.path().join("1")).await.unwrap(),
+            file_1_before
+        );
+        assert_eq!(db.get::<i32>(&"a".to_string()).await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn committed_merge_is_rolled_forward_on_open() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        for file in 1..=2 {
+            let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+            for i in 0..3 {
+                db.insert(format!("file {file}"), i).await.unwrap();
+            }
+            db.insert("deleted".to_string(), 0).await.unwrap();
+            db.remove("deleted".to_string()).await.unwrap();
+            drop(db);
+        }
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        // the process dies right after the merge is committed,
+        // before any of its files are swapped in
+        {
+            let base = db.base.read().await;
+            let outcome = base
+                .write_merge_files(&MergeControl::default(), MergeSelection::All)
+                .await
+                .unwrap();
+            outcome.manifest().write(dir.path()).await.unwrap();
+        }
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert!(merge_manifest::merge_file_paths(dir.path())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(!tokio::fs::try_exists(dir.path().join("merge.manifest"))
+            .await
+            .unwrap());
+
+        // both files' latest records fit in one merged file,
+        // alongside the active files made by each open
+        assert_eq!(get_files(&dir.path()).await.len(), 3);
+
+        for file in 1..=2 {
+            assert_eq!(
+                db.get::<i32>(&format!("file {file}")).await.unwrap(),
+                Some(2)
+            );
+        }
+        assert_eq!(db.get::<i32>(&"deleted".to_string()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn every_sync_behavior_roundtrips() {
+        for sync_behavior in [
+            SyncBehavior::Never,
+            SyncBehavior::AfterEveryWrite,
+            SyncBehavior::Interval(std::time::Duration::from_millis(1)),
+            SyncBehavior::Bytes(100),
+        ] {
+            let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+            let options = Options {
+                sync_behavior,
+                flush_behavior: FlushBehavior::WhenFull,
+                max_file_size_bytes: 200,
+                ..Default::default()
+            };
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+            for i in 0..20 {
+                db.insert(format!("k{i}"), i).await.unwrap();
+            }
+
+            db.remove("k0".to_string()).await.unwrap();
+
+            db.sync().await.unwrap();
+
+            // the active file rolled over
+            assert!(get_files(&dir.path()).await.len() > 1);
+
+            // rather than dropping it, so that the lock is released
+            // even if the sync task is partway through a sync
+            db.close().await.unwrap();
+
+            let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+            assert_eq!(db.get::<i32>(&"k0".to_string()).await.unwrap(), None);
+            for i in 1..20 {
+                assert_eq!(db.get::<i32>(&format!("k{i}")).await.unwrap(), Some(i));
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn queued_writes_are_written_as_one_group() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let base = db.base.read().await;
+
+        // the writes queue up behind this, in the order they are spawned
+        let active_file = base.lock_active_file().await;
+
+        let mut writers = vec![];
+
+        let db2 = db.clone();
+        writers.push(tokio::spawn(
+            async move { db2.insert("a".to_string(), 1).await },
+        ));
+        base.wait_for_queued_writes(1).await;
+
+        let db2 = db.clone();
+        writers.push(tokio::spawn(
+            async move { db2.remove("a".to_string()).await },
+        ));
+        base.wait_for_queued_writes(2).await;
+
+        let db2 = db.clone();
+        writers.push(tokio::spawn(async move {
+            let mut batch = WriteBatch::new();
+            batch.insert("b".to_string(), 2).unwrap();
+            batch.insert("c".to_string(), 3).unwrap();
+            db2.write_batch(batch).await
+        }));
+        base.wait_for_queued_writes(3).await;
+
+        drop(active_file);
+
+        for writer in writers {
+            writer.await.unwrap().unwrap();
+        }
+
+        // the remove saw the insert that was queued before it
+        assert!(!base.contains_key(&"a".to_string()));
+        assert_eq!(base.get::<i32>(&"b".to_string()).await.unwrap(), Some(2));
+        assert_eq!(base.get::<i32>(&"c".to_string()).await.unwrap(), Some(3));
+
+        drop(base);
+        drop(db);
+
+        let report = verify(dir.path()).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files[0].batches, 1);
+        assert_eq!(report.files[0].tombstones, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn writes_land_even_when_their_callers_stop_waiting() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            sync_behavior: SyncBehavior::AfterEveryWrite,
+            ..Default::default()
+        };
+
+        let db: Arc<B2<String>> = Arc::new(B2::open(dir.path(), options).await.unwrap());
+
+        let mut tasks = vec![];
+
+        for i in 0..50 {
+            let db = Arc::clone(&db);
+            tasks.push(tokio::spawn(async move {
+                if i % 2 == 0 {
+                    db.insert(format!("k{i}"), i).await.unwrap();
+                } else {
+                    // polled once, which is enough to queue the write, and then dropped
+                    let _ = tokio::time::timeout(
+                        std::time::Duration::ZERO,
+                        db.insert(format!("k{i}"), i),
+                    )
+                    .await;
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // waits for every task that is still writing
+        drop(db.base.write().await);
+
+        for i in 0..50 {
+            assert_eq!(db.get::<i32>(&format!("k{i}")).await.unwrap(), Some(i));
+        }
+
+        Arc::into_inner(db).unwrap().close().await.unwrap();
+
+        let report = verify(dir.path()).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files[0].live, 50);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_synced_writes_all_land() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            sync_behavior: SyncBehavior::AfterEveryWrite,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        db.insert(format!("{writer}-{i}"), i).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        for writer in 0..8 {
+            for i in 0..25 {
+                assert_eq!(
+                    db.get::<i32>(&format!("{writer}-{i}")).await.unwrap(),
+                    Some(i)
+                );
+            }
+        }
+
+        assert!(verify(dir.path()).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn interval_flush_flushes_without_being_asked() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            flush_behavior: FlushBehavior::Interval(std::time::Duration::from_millis(10)),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        let data_file = dir.path().join("1");
+
+        // the write sits in the buffer until the next tick
+        wait_until("the write buffer was never flushed", async || {
+            tokio::fs::metadata(&data_file).await.unwrap().len() != FileHeader::SIZE
+        })
+        .await;
+
+        assert_eq!(
+            db.file_stats().await[0].total_bytes,
+            tokio::fs::metadata(&data_file).await.unwrap().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn interval_sync_syncs_the_last_writes_before_going_idle() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            flush_behavior: FlushBehavior::WhenFull,
+            sync_behavior: SyncBehavior::Interval(std::time::Duration::from_millis(10)),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        let data_file = dir.path().join("1");
+
+        // nothing else is written, so only the background task can sync it,
+        // which flushes it first
+        wait_until("the write was never synced", async || {
+            tokio::fs::metadata(&data_file).await.unwrap().len() != FileHeader::SIZE
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn close_writes_out_the_write_buffer() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            flush_behavior: FlushBehavior::WhenFull,
+            sync_behavior: SyncBehavior::Interval(std::time::Duration::from_secs(3600)),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        let v: String = db.get(&"foo".to_string()).await.unwrap().unwrap();
+
+        assert_eq!(v, "bar");
+    }
+
+    #[tokio::test]
+    async fn dropping_without_close_still_writes_out_the_write_buffer() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            flush_behavior: FlushBehavior::WhenFull,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        for i in 0..10 {
+            db.insert(format!("key {i}"), i).await.unwrap();
+        }
+
+        drop(db);
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        for i in 0..10 {
+            let v: i32 = db.get(&format!("key {i}")).await.unwrap().unwrap();
+            assert_eq!(v, i);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_database_can_only_be_opened_once_at_a_time() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let files_before = get_files(&dir.path()).await;
+
+        let second = B2::<String>::open(dir.path(), Options::default()).await;
+
+        assert!(matches!(
+            second,
+            Err(error::Error::DatabaseLocked(db_directory)) if db_directory == dir.path()
+        ));
+
+        // the second open did not make an active file of its own
+        assert_eq!(get_files(&dir.path()).await, files_before);
+
+        db.close().await.unwrap();
+
+        let _db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_opens_do_not_change_the_directory() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+        db.insert("foo".to_string(), "baz".to_string())
+            .await
+            .unwrap();
+
+        let directory_before = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name(), entry.metadata().unwrap().len())
+            })
+            .collect::<std::collections::BTreeMap<_, _>>();
+
+        // the writer still has the database open
+        let reader: B2<String> = B2::open_read_only(dir.path(), Options::default())
+            .await
+            .unwrap();
+        let other_reader: B2<String> = B2::open_read_only(dir.path(), Options::default())
+            .await
+            .unwrap();
+
+        let v: String = reader.get(&"foo".to_string()).await.unwrap().unwrap();
+        assert_eq!(v, "baz");
+        assert!(other_reader.contains_key(&"foo".to_string()).await);
+
+        assert!(matches!(
+            reader.insert("foo".to_string(), 1).await,
+            Err(error::Error::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.remove("foo".to_string()).await,
+            Err(error::Error::ReadOnly)
+        ));
+        assert!(matches!(reader.merge().await, Err(error::Error::ReadOnly)));
+
+        reader.close().await.unwrap();
+        drop(other_reader);
+
+        let directory_after = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name(), entry.metadata().unwrap().len())
+            })
+            .collect::<std::collections::BTreeMap<_, _>>();
+
+        assert_eq!(directory_after, directory_before);
+
+        db.insert("foo".to_string(), "qux".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn followers_catch_up_with_writes_rollovers_and_merges() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            max_file_size_bytes: 200,
+            ..Default::default()
+        };
+
+        let writer: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        writer.insert("before".to_string(), 0).await.unwrap();
+
+        let follower: B2<String> = B2::open_follower(dir.path(), options.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(follower.get(&"before".to_string()).await.unwrap(), Some(0));
+
+        // enough to roll over to new files a few times
+        for i in 0..20 {
+            writer.insert(format!("key {}", i % 5), i).await.unwrap();
+        }
+        writer.remove("before".to_string()).await.unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .insert("batched".to_string(), "yes".to_string())
+            .unwrap();
+        writer.write_batch(batch).await.unwrap();
+
+        assert!(get_files(&dir.path()).await.len() > 2);
+
+        follower.catch_up().await.unwrap();
+
+        assert!(!follower.contains_key(&"before".to_string()).await);
+        for i in 15..20 {
+            assert_eq!(
+                follower.get(&format!("key {}", i % 5)).await.unwrap(),
+                Some(i)
+            );
+        }
+        assert_eq!(
+            follower.get(&"batched".to_string()).await.unwrap(),
+            Some("yes".to_string())
+        );
+
+        writer.merge().await.unwrap();
+        writer.insert("after".to_string(), 1).await.unwrap();
+
+        follower.catch_up().await.unwrap();
+
+        let mut keys = follower.keys().await;
+        keys.sort();
+        let mut expected = writer.keys().await;
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        for key in expected.iter().filter(|key| *key != "batched") {
+            assert_eq!(
+                follower.get::<i32>(key).await.unwrap(),
+                writer.get::<i32>(key).await.unwrap()
+            );
+        }
+
+        assert!(matches!(
+            follower.insert("nope".to_string(), 1).await,
+            Err(error::Error::ReadOnly)
+        ));
+    }
+
+    #[tokio::test]
+    async fn followers_read_the_right_values_right_after_the_writer_merges() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            max_file_size_bytes: 100,
+            ..Default::default()
+        };
+
+        let writer: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            writer
+                .insert(key.to_string(), format!("old-{key}"))
+                .await
+                .unwrap();
+        }
+        for key in ["a", "b", "c", "d"] {
+            writer
+                .insert(key.to_string(), format!("val-{key}"))
+                .await
+                .unwrap();
+        }
+
+        let follower: B2<String> = B2::open_follower(dir.path(), options).await.unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(
+                follower.get(&key.to_string()).await.unwrap(),
+                Some(format!("val-{key}"))
+            );
+        }
+
+        // the merge moves every live record, and removes the files the old ones were in
+        writer.merge().await.unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(
+                follower.get(&key.to_string()).await.unwrap(),
+                Some(format!("val-{key}"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_databases_never_read_the_wrong_value_after_the_writer_merges() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            max_file_size_bytes: 100,
+            ..Default::default()
+        };
+
+        let writer: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            writer
+                .insert(key.to_string(), format!("old-{key}"))
+                .await
+                .unwrap();
+        }
+        for key in ["a", "b", "c", "d"] {
+            writer
+                .insert(key.to_string(), format!("val-{key}"))
+                .await
+                .unwrap();
+        }
+
+        let read_only: B2<String> = B2::open_read_only(dir.path(), options.clone())
+            .await
+            .unwrap();
+
+        writer.merge().await.unwrap();
+
+        let mut moved = 0;
+        for key in ["a", "b", "c", "d"] {
+            match read_only.get::<String>(&key.to_string()).await {
+                Ok(value) => assert_eq!(value, Some(format!("val-{key}"))),
+                Err(error::Error::RecordMoved) => moved += 1,
+                Err(e) => panic!("{e}"),
+            }
+        }
+        assert!(moved > 0);
+
+        let read_only: B2<String> = B2::open_read_only(dir.path(), options).await.unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(
+                read_only.get(&key.to_string()).await.unwrap(),
+                Some(format!("val-{key}"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_catch_up_on_their_own() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let writer: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let options = Options {
+            follow_interval: std::time::Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        let follower: B2<String> = B2::open_follower(dir.path(), options).await.unwrap();
+
+        writer.insert("foo".to_string(), 1).await.unwrap();
+
+        wait_until("the follower never caught up", async || {
+            follower.contains_key(&"foo".to_string()).await
+        })
+        .await;
+
+        assert_eq!(follower.get(&"foo".to_string()).await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn followers_notice_merges_that_leave_files_looking_the_same() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let writer: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        for n in 0..20 {
+            writer
+                .insert(format!("key {n}"), "x".repeat(n))
+                .await
+                .unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let writer: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let follower: B2<String> = B2::open_follower(dir.path(), Options::default())
+            .await
+            .unwrap();
+
+        let data_file = dir.path().join("1");
+        let modified = tokio::fs::metadata(&data_file)
+            .await
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        // nothing in file 1 is dead, so merging it only moves its records around,
+        // and it is just as long as it was
+        writer.merge_files(&[1.into()]).await.unwrap();
+
+        std::fs::File::options()
+            .write(true)
+            .open(&data_file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        follower.catch_up().await.unwrap();
+
+        for n in 0..20 {
+            assert_eq!(
+                follower.get(&format!("key {n}")).await.unwrap(),
+                Some("x".repeat(n))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_absent_and_dropped_by_merge() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let ttl = std::time::Duration::from_millis(100);
+
+        db.insert("forever".to_string(), 1).await.unwrap();
+        // an older value that the expired one must keep shadowing
+        db.insert("expiring".to_string(), 1).await.unwrap();
+        db.insert_with_ttl("expiring".to_string(), 2, ttl)
+            .await
+            .unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .insert_with_ttl("batched".to_string(), 3, ttl)
+            .unwrap();
+        db.write_batch(batch).await.unwrap();
+
+        assert_eq!(db.get(&"expiring".to_string()).await.unwrap(), Some(2));
+        assert!(db.contains_key(&"batched".to_string()).await);
+
+        tokio::time::sleep(ttl * 2).await;
+
+        assert_eq!(db.get::<i32>(&"expiring".to_string()).await.unwrap(), None);
+        assert!(!db.contains_key(&"expiring".to_string()).await);
+        assert!(!db.contains_key(&"batched".to_string()).await);
+        assert_eq!(db.keys().await, vec!["forever".to_string()]);
+
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<i32>(&"expiring".to_string()).await.unwrap(), None);
+        assert_eq!(db.keys().await, vec!["forever".to_string()]);
+
+        db.merge().await.unwrap();
+
+        let report = verify(dir.path()).await.unwrap();
+        let records: u64 = report.files.iter().map(|file| file.records).sum();
+        assert_eq!(records, 1);
+
+        assert_eq!(db.get::<i32>(&"expiring".to_string()).await.unwrap(), None);
+        assert_eq!(db.keys().await, vec!["forever".to_string()]);
+
+        db.insert("expiring".to_string(), 4).await.unwrap();
+        assert_eq!(db.get(&"expiring".to_string()).await.unwrap(), Some(4));
+    }
+
+    #[tokio::test]
+    async fn metadata_survives_reopening_and_merging() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.metadata(&"a".to_string()).await, None);
+
+        // timestamps are stored in whole milliseconds
+        let before = std::time::SystemTime::now() - std::time::Duration::from_millis(1);
+        db.insert("a".to_string(), 1u64).await.unwrap();
+        db.insert_with_ttl("b".to_string(), 2u64, std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+        let after = std::time::SystemTime::now();
+
+        let a = db.metadata(&"a".to_string()).await.unwrap();
+        let b = db.metadata(&"b".to_string()).await.unwrap();
+
+        assert!(before <= a.timestamp && a.timestamp <= after);
+        assert!(a.timestamp <= b.timestamp);
+        assert!(a.tx_id < b.tx_id);
+        assert_eq!(a.value_size, 8);
+        assert_eq!(a.expires_at, None);
+        assert!(b.expires_at.unwrap() > after);
+
+        db.insert("a".to_string(), 3u64).await.unwrap();
+        let a_again = db.metadata(&"a".to_string()).await.unwrap();
+        assert!(a_again.tx_id > b.tx_id);
+        assert!(a_again.timestamp >= a.timestamp);
+
+        db.remove("b".to_string()).await.unwrap();
+        assert_eq!(db.metadata(&"b".to_string()).await, None);
+
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.metadata(&"a".to_string()).await, Some(a_again));
+
+        db.merge().await.unwrap();
+        let merged = db.metadata(&"a".to_string()).await.unwrap();
+        assert_eq!(merged.tx_id, a_again.tx_id);
+        assert_eq!(merged.timestamp, a_again.timestamp);
+        assert_eq!(merged.value_size, a_again.value_size);
+
+        db.close().await.unwrap();
+
+        // loaded from the hint file that merge wrote
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.metadata(&"a".to_string()).await, Some(merged));
+    }
+
+    #[tokio::test]
+    async fn tx_ids_are_never_reused_after_deletes_and_merges() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), 1).await.unwrap();
+        let first_tx_id = db.metadata(&"a".to_string()).await.unwrap().tx_id;
+        // the newest record is a delete
+        db.remove("a".to_string()).await.unwrap();
+
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), 2).await.unwrap();
+        let second_tx_id = db.metadata(&"a".to_string()).await.unwrap().tx_id;
+        assert!(second_tx_id > first_tx_id + 1);
+
+        db.close().await.unwrap();
+
+        // the insert has to win over the older delete
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(2));
+
+        let mut batch = WriteBatch::new();
+        batch.remove("a".to_string()).unwrap();
+        db.write_batch(batch).await.unwrap();
+
+        db.close().await.unwrap();
+
+        // merging everything drops every record
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.merge().await.unwrap();
+        let report = verify(dir.path()).await.unwrap();
+        assert_eq!(report.files.iter().map(|file| file.records).sum::<u64>(), 0);
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("a".to_string(), 3).await.unwrap();
+        let third_tx_id = db.metadata(&"a".to_string()).await.unwrap().tx_id;
+        // past the batch's remove, and the record that committed the batch
+        assert!(third_tx_id > second_tx_id + 2);
+    }
+
+    #[tokio::test]
+    async fn data_files_start_with_a_header_that_is_checked() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("a".to_string(), 1).await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        // the merged file, and the active file that was never written to
+        for file_id in [1, 2] {
+            let bytes = tokio::fs::read(dir.path().join(file_id.to_string()))
+                .await
+                .unwrap();
+            assert!(bytes.len() as u64 >= FileHeader::SIZE);
+            assert_eq!(&bytes[..4], b"b2db");
+            assert_eq!(&bytes[4..6], &FileHeader::CURRENT_VERSION.to_be_bytes());
+        }
+
+        // a newer format version
+        let data_file = dir.path().join("1");
+        let original = tokio::fs::read(&data_file).await.unwrap();
+        let mut bytes = original.clone();
+        bytes[4..6].copy_from_slice(&(FileHeader::CURRENT_VERSION + 1).to_be_bytes());
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        assert!(matches!(
+            B2::<String>::open(dir.path(), Options::default()).await,
+            Err(error::Error::UnsupportedFormatVersion(path, version))
+                if path == data_file && version == FileHeader::CURRENT_VERSION + 1
+        ));
+        assert!(matches!(
+            verify(dir.path()).await,
+            Err(error::Error::UnsupportedFormatVersion(..))
+        ));
+
+        tokio::fs::write(&data_file, &original).await.unwrap();
+
+        // something that only happens to be named like a data file
+        let not_a_data_file = dir.path().join("7");
+        tokio::fs::write(&not_a_data_file, b"hello").await.unwrap();
+
+        assert!(matches!(
+            B2::<String>::open(dir.path(), Options::default()).await,
+            Err(error::Error::NotADataFile(path)) if path == not_a_data_file
+        ));
+
+        tokio::fs::remove_file(&not_a_data_file).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn every_codec_roundtrips_through_reopening_and_merging() {
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        struct Value {
+            name: String,
+            n: u64,
+        }
+
+        let codecs = [
+            CodecKind::Bincode,
+            #[cfg(feature = "postcard")]
+            CodecKind::Postcard,
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack,
+            #[cfg(feature = "json")]
+            CodecKind::Json,
+        ];
+
+        for codec in codecs {
+            let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+            let options = Options {
+                codec,
+                ..Default::default()
+            };
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+            let value = |n| Value {
+                name: format!("value {n}"),
+                n,
+            };
+
+            db.insert("a".to_string(), value(1)).await.unwrap();
+            db.insert("a".to_string(), value(2)).await.unwrap();
+            db.insert("b".to_string(), value(3)).await.unwrap();
+
+            let mut batch = db.new_batch();
+            batch.insert("c".to_string(), value(4)).unwrap();
+            batch.remove("b".to_string()).unwrap();
+            db.write_batch(batch).await.unwrap();
+
+            db.close().await.unwrap();
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+            db.merge().await.unwrap();
+            db.close().await.unwrap();
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+            assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(value(2)));
+            assert_eq!(db.get::<Value>(&"b".to_string()).await.unwrap(), None);
+            assert_eq!(db.get(&"c".to_string()).await.unwrap(), Some(value(4)));
+
+            let mut keys = db.keys().await;
+            keys.sort();
+            assert_eq!(keys, vec!["a".to_string(), "c".to_string()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn opening_with_a_different_codec_is_an_error() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("a".to_string(), 1).await.unwrap();
+        db.close().await.unwrap();
+
+        // as if it had been written with some other codec
+        let data_file = dir.path().join("1");
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        bytes[6] = 3;
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        assert!(matches!(
+            B2::<String>::open(dir.path(), Options::default()).await,
+            Err(error::Error::CodecMismatch(path)) if path == data_file
+        ));
+    }
+
+    #[tokio::test]
+    async fn bincode_errors_keep_their_bincode_source() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("a".to_string(), 1u8).await.unwrap();
+
+        match db.get::<String>(&"a".to_string()).await {
+            Err(error::Error::DeserializeError(error::DeserializeError { source, .. })) => {
+                assert!(matches!(*source, bincode::ErrorKind::Io(_)))
+            }
+            other => panic!("expected a bincode error, got {other:?}"),
+        }
+
+        #[cfg(feature = "json")]
+        assert!(matches!(
+            CodecKind::Json.decode::<u8>(b"not json"),
+            Err(error::Error::CodecDeserializeError(_))
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_values_are_readable_as_json() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            codec: CodecKind::Json,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        db.insert("a".to_string(), vec![1, 2, 3]).await.unwrap();
+
+        let bytes = tokio::fs::read(dir.path().join("1")).await.unwrap();
+        assert!(bytes.ends_with(br#""a"[1,2,3]"#));
+
+        // a batch built for the default codec
+        let mut batch = WriteBatch::new();
+        batch.insert("b".to_string(), 1).unwrap();
+        assert!(matches!(
+            db.write_batch(batch).await,
+            Err(error::Error::BatchCodecMismatch)
+        ));
+
+        let mut batch = db.new_batch();
+        batch.insert("b".to_string(), 1).unwrap();
+        db.write_batch(batch).await.unwrap();
+        assert_eq!(db.get(&"b".to_string()).await.unwrap(), Some(1));
+
+        db.close().await.unwrap();
+
+        assert!(matches!(
+            B2::<String>::open(dir.path(), Options::default()).await,
+            Err(error::Error::CodecMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn every_compression_roundtrips_through_reopening_and_merging() {
+        let compressions = [
+            Compression::None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ];
+
+        let big = "x".repeat(4096);
+        let big_size = codec::Bincode.encode(&big).unwrap().len() as u64;
+        let small = "small".to_string();
+        let small_size = codec::Bincode.encode(&small).unwrap().len() as u64;
+
+        for compression in compressions {
+            let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+            let options = Options {
+                compression,
+                ..Default::default()
+            };
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+            db.insert("big".to_string(), big.clone()).await.unwrap();
+            db.insert("small".to_string(), small.clone()).await.unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch
+                .insert("big batched".to_string(), big.clone())
+                .unwrap();
+            db.write_batch(batch).await.unwrap();
+
+            db.remove("small".to_string()).await.unwrap();
+            db.insert("small".to_string(), small.clone()).await.unwrap();
+
+            for key in ["big", "big batched"] {
+                let value_size = db.metadata(&key.to_string()).await.unwrap().value_size;
+
+                if compression == Compression::None {
+                    assert_eq!(value_size, big_size);
+                } else {
+                    assert!(value_size < big_size);
+                }
+            }
+
+            // below the threshold, so never compressed
+            assert_eq!(
+                db.metadata(&"small".to_string()).await.unwrap().value_size,
+                small_size
+            );
+
+            db.close().await.unwrap();
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+            db.merge().await.unwrap();
+            db.close().await.unwrap();
+
+            let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+            assert_eq!(db.get(&"big".to_string()).await.unwrap(), Some(big.clone()));
+            assert_eq!(
+                db.get(&"big batched".to_string()).await.unwrap(),
+                Some(big.clone())
+            );
+            assert_eq!(
+                db.get(&"small".to_string()).await.unwrap(),
+                Some(small.clone())
+            );
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn merge_recompresses_records_when_compression_changes() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let big = "x".repeat(4096);
+        let big_size = codec::Bincode.encode(&big).unwrap().len() as u64;
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("a".to_string(), big.clone()).await.unwrap();
+        db.close().await.unwrap();
+
+        let compressed = Options {
+            compression: Compression::Zstd(3),
+            ..Default::default()
+        };
+
+        // records written before the change are read as they are
+        let db: B2<String> = B2::open(dir.path(), compressed.clone()).await.unwrap();
+        db.insert("b".to_string(), big.clone()).await.unwrap();
+        assert_eq!(
+            db.metadata(&"a".to_string()).await.unwrap().value_size,
+            big_size
+        );
+        assert!(db.metadata(&"b".to_string()).await.unwrap().value_size < big_size);
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(big.clone()));
+        db.close().await.unwrap();
+
+        // and merge compresses them
+        let db: B2<String> = B2::open(dir.path(), compressed.clone()).await.unwrap();
+        db.merge().await.unwrap();
+        assert!(db.metadata(&"a".to_string()).await.unwrap().value_size < big_size);
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), compressed.clone()).await.unwrap();
+        assert!(db.metadata(&"a".to_string()).await.unwrap().value_size < big_size);
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(big.clone()));
+        db.close().await.unwrap();
+
+        // turning compression off leaves them compressed, as decompressing them
+        // would only make them larger
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        for key in ["a", "b"] {
+            assert!(db.metadata(&key.to_string()).await.unwrap().value_size < big_size);
+            assert_eq!(db.get(&key.to_string()).await.unwrap(), Some(big.clone()));
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn merging_after_turning_compression_off_keeps_every_record() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let value = |i: usize| i.to_string().repeat(4000);
+
+        let compressed = Options {
+            compression: Compression::Zstd(3),
+            max_file_size_bytes: 100,
+            ..Default::default()
+        };
+
+        // one record per file, each much smaller than its value
+        let db: B2<String> = B2::open(dir.path(), compressed).await.unwrap();
+        for i in 0..5 {
+            db.insert(format!("k{i}"), value(i)).await.unwrap();
+        }
+        db.close().await.unwrap();
+
+        let uncompressed = Options {
+            max_file_size_bytes: 100,
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), uncompressed.clone()).await.unwrap();
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), uncompressed).await.unwrap();
+        for i in 0..5 {
+            assert_eq!(db.get(&format!("k{i}")).await.unwrap(), Some(value(i)));
+        }
+        assert!(verify(dir.path()).await.unwrap().is_ok());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn encrypted_databases_store_no_plaintext() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            encryption_key: Some(EncryptionKey::generate()),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        db.insert("secret key 1".to_string(), "secret value 1".to_string())
+            .await
+            .unwrap();
+        db.insert("secret key 2".to_string(), "secret value 2".to_string())
+            .await
+            .unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .insert("secret key 3".to_string(), "secret value 3".to_string())
+            .unwrap();
+        batch.remove("secret key 2".to_string()).unwrap();
+        db.write_batch(batch).await.unwrap();
+
+        db.close().await.unwrap();
+
+        // so that there are hint files too
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.merge().await.unwrap();
+        db.insert("secret key 4".to_string(), "secret value 4".to_string())
+            .await
+            .unwrap();
+        db.close().await.unwrap();
+
+        let mut s = tokio::fs::read_dir(dir.path()).await.unwrap();
+        while let Some(e) = s.next_entry().await.unwrap() {
+            let bytes = tokio::fs::read(e.path()).await.unwrap();
+            assert!(!bytes.windows(6).any(|w| w == b"secret"));
+        }
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        for n in [1, 3, 4] {
+            assert_eq!(
+                db.get(&format!("secret key {n}")).await.unwrap(),
+                Some(format!("secret value {n}"))
+            );
+        }
+        assert_eq!(
+            db.get::<String>(&"secret key 2".to_string()).await.unwrap(),
+            None
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn encrypted_values_swapped_between_records_fail_to_decrypt() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            encryption_key: Some(EncryptionKey::generate()),
+            ..Default::default()
+        };
+
+        // keys and values all the same length, so both records are too
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.insert("a".to_string(), "x".to_string()).await.unwrap();
+        db.insert("b".to_string(), "y".to_string()).await.unwrap();
+        db.close().await.unwrap();
+
+        let data_file = get_files(&dir.path()).await.pop().unwrap();
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+
+        let records_start = FileHeader::SIZE as usize;
+        let record_len = (bytes.len() - records_start) / 2;
+        let header_size =
+            record::Record::header_size_for(SizeFields::Compact, FileHeader::CURRENT_VERSION);
+        // the key and value are sealed to the same length
+        let value_len = (record_len - header_size) / 2;
+
+        let first = records_start..records_start + record_len;
+        let second = first.end..first.end + record_len;
+
+        let first_value = first.end - value_len..first.end;
+        let second_value = second.end - value_len..second.end;
+
+        let value = bytes[first_value.clone()].to_vec();
+        bytes.copy_within(second_value.clone(), first_value.start);
+        bytes[second_value].copy_from_slice(&value);
+
+        // so that the records are not just seen as corrupt
+        for record in [first, second] {
+            let hash = crc32fast::hash(&bytes[record.start + 4..record.end]);
+            bytes[record.start..record.start + 4].copy_from_slice(&hash.to_be_bytes());
+        }
+
+        tokio::fs::write(&data_file, bytes).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        for k in ["a", "b"] {
+            assert!(matches!(
+                db.get::<String>(&k.to_string()).await,
+                Err(error::Error::DecryptionFailed)
+            ));
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn encrypted_record_headers_can_not_be_changed_unnoticed() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            encryption_key: Some(EncryptionKey::generate()),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.insert("a".to_string(), "x".to_string()).await.unwrap();
+        db.close().await.unwrap();
+
+        let data_file = get_files(&dir.path()).await.pop().unwrap();
+        let original = tokio::fs::read(&data_file).await.unwrap();
+
+        let record = FileHeader::SIZE as usize;
+        // hash(4) kind(1) tx_id(16) timestamp(8) expires_at(8)
+        let kind = record + 4;
+        let timestamp = kind + 1 + 16;
+        let expires_at = timestamp + 8;
+
+        // changes the header, and recomputes the hash so that the record is not just corrupt
+        let tamper = |change: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = original.clone();
+            change(&mut bytes);
+            let hash = crc32fast::hash(&bytes[record + 4..]);
+            bytes[record..record + 4].copy_from_slice(&hash.to_be_bytes());
+            tokio::fs::write(&data_file, bytes)
+        };
+
+        tamper(&|bytes| bytes[timestamp + 7] ^= 1).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        assert!(matches!(
+            db.get::<String>(&"a".to_string()).await,
+            Err(error::Error::DecryptionFailed)
+        ));
+        drop(db);
+
+        // an expired value is never read, so it takes verifying to notice
+        tamper(&|bytes| bytes[expires_at + 7] = 1).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        assert_eq!(db.get::<String>(&"a".to_string()).await.unwrap(), None);
+        drop(db);
+        assert!(matches!(
+            verify_with(dir.path(), &options).await,
+            Err(error::Error::DecryptionFailed)
+        ));
+
+        // a delete has no value
+        tamper(&|bytes| bytes[kind] |= 0x40).await.unwrap();
+
+        let strict = Options {
+            recovery_mode: RecoveryMode::Strict,
+            ..options.clone()
+        };
+        assert!(matches!(
+            B2::<String>::open(dir.path(), strict).await,
+            Err(error::Error::CorruptRecord)
+        ));
+
+        tokio::fs::write(&data_file, &original).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+        assert_eq!(
+            db.get(&"a".to_string()).await.unwrap(),
+            Some("x".to_string())
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn opening_with_the_wrong_encryption_key_is_an_error() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            encryption_key: Some(EncryptionKey::new([1; 32])),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.insert("a".to_string(), 1).await.unwrap();
+        db.close().await.unwrap();
+
+        // a different key fails to authenticate, rather than reading garbage
+        let wrong_key = Options {
+            encryption_key: Some(EncryptionKey::new([2; 32])),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            B2::<String>::open(dir.path(), wrong_key).await,
+            Err(error::Error::DecryptionFailed)
+        ));
+
+        // and no key at all is caught by the file header
+        assert!(matches!(
+            B2::<String>::open(dir.path(), Options::default()).await,
+            Err(error::Error::EncryptionMismatch(_))
+        ));
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(1));
+        db.close().await.unwrap();
+
+        // as is a key for a database that was never encrypted
+        let plaintext_dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(plaintext_dir.path(), Options::default())
+            .await
+            .unwrap();
+        db.insert("a".to_string(), 1).await.unwrap();
+        db.close().await.unwrap();
+
+        assert!(matches!(
+            B2::<String>::open(plaintext_dir.path(), options).await,
+            Err(error::Error::EncryptionMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keys_and_values_over_the_limits_are_rejected() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            max_value_size: Some(100),
+            ..Default::default()
+        };
+
+        assert_eq!(options.key_size_limit(), u16::MAX as u64);
+        assert_eq!(options.value_size_limit(), 100);
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+
+        // too large for a 2-byte key size, rather than silently truncated
+        let big_key = "k".repeat(70_000);
+
+        assert!(matches!(
+            db.insert(big_key.clone(), 1).await,
+            Err(error::Error::KeyTooLarge { size: 70_008, limit }) if limit == u16::MAX as u64
+        ));
+        assert!(matches!(
+            db.remove(big_key.clone()).await,
+            Err(error::Error::KeyTooLarge { .. })
+        ));
+        assert!(matches!(
+            db.insert("a".to_string(), "v".repeat(200)).await,
+            Err(error::Error::ValueTooLarge {
+                size: 208,
+                limit: 100
+            })
+        ));
+
+        // and none of a batch is written if any of it is too large
+        let mut batch = WriteBatch::new();
+        batch.insert("b".to_string(), "v".repeat(10)).unwrap();
+        batch.insert("c".to_string(), "v".repeat(200)).unwrap();
+        assert!(matches!(
+            db.write_batch(batch).await,
+            Err(error::Error::ValueTooLarge { .. })
+        ));
+
+        db.insert("d".to_string(), "v".repeat(10)).await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+        assert_eq!(db.keys().await, vec!["d".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn wide_size_fields_hold_larger_keys() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            size_fields: SizeFields::Wide,
+            ..Default::default()
+        };
+
+        assert_eq!(options.key_size_limit(), u32::MAX as u64);
+        assert_eq!(options.value_size_limit(), u64::MAX);
+
+        let big_key = "k".repeat(70_000);
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.insert(big_key.clone(), 1).await.unwrap();
+        db.insert("a".to_string(), 2).await.unwrap();
+        db.remove("a".to_string()).await.unwrap();
+        db.insert(big_key.clone(), 3).await.unwrap();
+        db.close().await.unwrap();
+
+        // so that the keydir is loaded from hint files
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        assert_eq!(db.get(&big_key).await.unwrap(), Some(3));
+        assert_eq!(db.keys().await, vec![big_key.clone()]);
+        assert!(verify(dir.path()).await.unwrap().is_ok());
+        db.close().await.unwrap();
+
+        // the data files say which size fields they were written with
+        assert!(matches!(
+            B2::<String>::open(dir.path(), Options::default()).await,
+            Err(error::Error::SizeFieldsMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn any_value_can_be_inserted_now_that_deletes_are_flagged() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        // what a delete used to be stored as
+        let value = b"bitcask_tombstone".to_vec();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("a".to_string(), value.clone()).await.unwrap();
+        db.insert("b".to_string(), 1).await.unwrap();
+        db.remove("b".to_string()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(value.clone()));
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(value.clone()));
+        assert_eq!(db.keys().await, vec!["a".to_string()]);
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(value));
+        assert_eq!(db.get::<i32>(&"b".to_string()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn databases_written_before_data_files_had_headers_still_open() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        write_headerless_fixture(dir.path()).await;
+
+        let report = verify(dir.path()).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(
+            report.files.iter().map(|file| file.tombstones).sum::<u64>(),
+            1
+        );
+
+        let read_only: B2<String> = B2::open_read_only(dir.path(), Options::default())
+            .await
+            .unwrap();
+        assert_eq!(read_only.get(&"a".to_string()).await.unwrap(), Some(4));
+        drop(read_only);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        let mut keys = db.keys().await;
+        keys.sort();
+        assert_eq!(keys, vec!["a", "c", "d"]);
+
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(4));
+        assert_eq!(db.get::<String>(&"b".to_string()).await.unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).await.unwrap(), Some(3));
+        assert_eq!(
+            db.get(&"d".to_string()).await.unwrap(),
+            Some("five".to_string())
+        );
+
+        // new tx ids carry on from the old ones
+        db.insert("a".to_string(), 6).await.unwrap();
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(6));
+
+        // a headerless file is only ever read with the codec that wrote it
+        drop(db);
+        #[cfg(feature = "postcard")]
+        assert!(matches!(
+            B2::<String>::open(
+                dir.path(),
+                Options {
+                    codec: CodecKind::Postcard,
+                    ..Default::default()
+                }
+            )
+            .await,
+            Err(error::Error::CodecMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn file_stats_count_records_written_before_data_files_had_headers() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        write_headerless_fixture(dir.path()).await;
+
+        // without the delete of "b", file 1 has a = 1, which is dead, and b, which is live
+        tokio::fs::remove_file(dir.path().join("4")).await.unwrap();
+        tokio::fs::remove_file(dir.path().join("5")).await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        // a = 1 is the same size as a = 4, which is alone in file 3
+        let a_record_len = tokio::fs::metadata(dir.path().join("3"))
+            .await
+            .unwrap()
+            .len();
+
+        let stats = db.file_stats().await;
+        assert_eq!(stats[0].dead_bytes, a_record_len);
+
+        db.remove("b".to_string()).await.unwrap();
+
+        let stats = db.file_stats().await;
+        assert_eq!(stats[0].dead_bytes, stats[0].total_bytes);
+    }
+
+    #[tokio::test]
+    async fn merge_migrates_databases_written_before_data_files_had_headers() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        write_headerless_fixture(dir.path()).await;
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        // the delete of "b" is kept, as the insert it deletes is in a file that isn't merged
+        db.merge_files(&[FileId::from(4)]).await.unwrap();
+
+        let bytes = tokio::fs::read(dir.path().join("4")).await.unwrap();
+        assert_eq!(&bytes[..4], b"b2db");
+        assert_eq!(&bytes[4..6], &FileHeader::CURRENT_VERSION.to_be_bytes());
+        assert!(!bytes
+            .windows(record::Record::legacy_tombstone().len())
+            .any(|window| window == record::Record::legacy_tombstone()));
+
+        let report = verify(dir.path()).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files[3].tombstones, 1);
+
+        db.close().await.unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert!(!db.contains_key(&"b".to_string()).await);
+
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        for path in get_files(&dir.path()).await {
+            let bytes = tokio::fs::read(&path).await.unwrap();
+            assert_eq!(&bytes[4..6], &FileHeader::CURRENT_VERSION.to_be_bytes());
+        }
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get(&"a".to_string()).await.unwrap(), Some(4));
+        assert_eq!(db.get::<String>(&"b".to_string()).await.unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).await.unwrap(), Some(3));
+        assert_eq!(
+            db.get(&"d".to_string()).await.unwrap(),
+            Some("five".to_string())
+        );
+        assert!(verify(dir.path()).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn version_1_data_files_are_read_and_migrated_by_merge() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        // a data file as version 1 wrote it, with a delete stored as a magic value
+        let header = FileHeader {
+            version: 1,
+            codec: 0,
+            flags: 0,
+            created_at: record::Timestamp::now(),
+        };
+
+        let mut bytes = header.to_bytes().to_vec();
+
+        let writes: [(&str, &[u8]); 4] = [
+            ("a", &crate::codec::Bincode.encode(&1).unwrap()),
+            ("b", &crate::codec::Bincode.encode(&2).unwrap()),
+            ("a", record::Record::legacy_tombstone()),
+            ("c", &crate::codec::Bincode.encode(&3).unwrap()),
+        ];
+
+        for (tx_id, (key, value)) in writes.into_iter().enumerate() {
+            let record = record::Record::from_encoded(
+                SizeFields::Compact,
+                record::RecordKind::Standalone,
+                compression::CompressionKind::None,
+                (tx_id as u128 + 1).into(),
+                record::Timestamp::now(),
+                record::ExpiresAt::NEVER,
+                &crate::codec::Bincode.encode(&key.to_string()).unwrap(),
+                value,
+            );
+            bytes.extend_from_slice(&record);
+        }
+
+        tokio::fs::write(dir.path().join("1"), &bytes)
+            .await
+            .unwrap();
+
+        let report = verify(dir.path()).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files[0].tombstones, 1);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get::<i32>(&"a".to_string()).await.unwrap(), None);
+        assert_eq!(db.get(&"b".to_string()).await.unwrap(), Some(2));
+        db.insert("d".to_string(), 4).await.unwrap();
+        db.merge().await.unwrap();
+        db.close().await.unwrap();
+
+        for path in get_files(&dir.path()).await {
+            let bytes = tokio::fs::read(&path).await.unwrap();
+            assert_eq!(&bytes[4..6], &FileHeader::CURRENT_VERSION.to_be_bytes());
+        }
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get::<i32>(&"a".to_string()).await.unwrap(), None);
+        assert_eq!(db.get(&"b".to_string()).await.unwrap(), Some(2));
+        assert_eq!(db.get(&"c".to_string()).await.unwrap(), Some(3));
+        assert_eq!(db.get(&"d".to_string()).await.unwrap(), Some(4));
+        assert!(verify(dir.path()).await.unwrap().is_ok());
+    }
+
+    /// the data files of a database written by b2 before data files had headers:
+    /// "a" inserted and then overwritten, "b" inserted and then removed,
+    /// "c" and "d" inserted, and a last, empty active file
+    async fn write_headerless_fixture(dir: &Path) {
+        let files: [&[u8]; 6] = [
+            include_bytes!("../tests/fixtures/baseline/1"),
+            include_bytes!("../tests/fixtures/baseline/2"),
+            include_bytes!("../tests/fixtures/baseline/3"),
+            include_bytes!("../tests/fixtures/baseline/4"),
+            include_bytes!("../tests/fixtures/baseline/5"),
+            include_bytes!("../tests/fixtures/baseline/6"),
+        ];
+
+        for (i, bytes) in files.into_iter().enumerate() {
+            tokio::fs::write(dir.join((i + 1).to_string()), bytes)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// checks `done` every 10ms, and panics with `message` if it is not done within 5 seconds
+    pub(crate) async fn wait_until(message: &str, mut done: impl AsyncFnMut() -> bool) {
+        for _ in 0..500 {
+            if done().await {
+                return;
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        panic!("{message}");
+    }
+
     async fn get_files<P: AsRef<Path>>(dir: &P) -> Vec<PathBuf> {
         let mut s = tokio::fs::read_dir(dir).await.unwrap();
 
         let mut entries = vec![];
 
         while let Some(e) = s.next_entry().await.unwrap() {
-            if e.path().is_file() {
+            // only data files, not hint files or other bookkeeping
+            if e.path().is_file()
+                && e.path()
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.parse::<u32>().is_ok())
+            {
                 entries.push(e.path());
             }
         }
@@ -1,7 +1,13 @@
+use crate::encoding::Encoding;
+use crate::file_header::FileHeader;
+use crate::hint::Hint;
 use crate::keydir::FileId;
+use crate::record::{RecordKind, TxId};
+use crate::recovery::{DroppedRange, RecoveryReport};
+use crate::RecoveryMode;
 use std::hash::Hash;
 use std::{collections::HashMap, path::Path};
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncSeekExt};
 
 /// a trait that expresses that a type knows how to read
 /// exactly one instance of itself from a file
@@ -10,25 +16,174 @@ pub(crate) trait Loadable<K: Eq + Hash>: PartialOrd + Sized {
         reader: &mut tokio::io::BufReader<R>,
         offset: &mut u64,
         file_id: FileId,
+        encoding: &Encoding,
+    ) -> crate::Result<Option<Loaded<K, Self>>>
+    where
+        Self: Sized;
+
+    /// like `read_one`, but reads from the hint file of a data file
+    /// rather than from the data file itself
+    async fn read_one_hint<R: AsyncRead + Unpin>(
+        reader: &mut tokio::io::BufReader<R>,
+        file_id: FileId,
+        encoding: &Encoding,
     ) -> crate::Result<Option<(K, Self)>>
     where
         Self: Sized;
+
+    fn tx_id(&self) -> TxId;
+}
+
+/// one record's worth of a data file
+pub(crate) enum Loaded<K, L> {
+    /// an insert or delete that takes effect on its own
+    Entry(K, L),
+    /// an insert or delete that only takes effect
+    /// if the `BatchCommit` for its batch is also loaded
+    BatchEntry(K, L),
+    /// the end of a batch, with the number of records in it,
+    /// and the commit record's own `TxId`
+    BatchCommit(u32, TxId),
+}
+
+impl<K, L> Loaded<K, L> {
+    pub(crate) fn new(kind: RecordKind, key: K, l: L) -> Self {
+        if kind == RecordKind::Batched {
+            Loaded::BatchEntry(key, l)
+        } else {
+            Loaded::Entry(key, l)
+        }
+    }
+}
+
+/// The records of a batch whose `BatchCommit` has not been read yet.
+///
+/// A batch is always written in one piece, so its records can't be followed
+/// by anything but its commit. If something else follows them, the batch
+/// was never committed, and none of it takes effect.
+pub(crate) struct PendingBatch<T> {
+    records: Vec<T>,
+    /// the offset of the first record of the batch
+    offset: Option<u64>,
+}
+
+impl<T> PendingBatch<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            records: vec![],
+            offset: None,
+        }
+    }
+
+    /// a record of the batch, read at `offset`
+    pub(crate) fn push(&mut self, offset: u64, record: T) {
+        self.offset.get_or_insert(offset);
+        self.records.push(record);
+    }
+
+    /// Read a record that is not part of the batch, so the batch was never committed.
+    /// Returns how many records it had.
+    pub(crate) fn abandon(&mut self) -> usize {
+        let len = self.records.len();
+        self.records.clear();
+        self.offset = None;
+        len
+    }
+
+    /// `abandon`, for a record that is not part of the batch read at `offset`,
+    /// reporting the batch as dropped
+    pub(crate) fn drop_uncommitted(
+        &mut self,
+        file_id: FileId,
+        offset: u64,
+        recovery_report: &mut RecoveryReport,
+    ) {
+        if let Some(batch_offset) = self.offset {
+            recovery_report.drop_batch(file_id, batch_offset, offset);
+        }
+
+        self.abandon();
+    }
+
+    /// Read the commit of a batch of `len` records.
+    /// Returns its records, unless some of them were skipped as corrupt,
+    /// in which case none of the batch can take effect.
+    pub(crate) fn commit(&mut self, len: u32) -> Option<Vec<T>> {
+        self.offset = None;
+
+        let records = std::mem::take(&mut self.records);
+
+        if records.len() == len as usize {
+            Some(records)
+        } else {
+            None
+        }
+    }
+
+    /// `commit`, for a commit that ends at `end`,
+    /// reporting the batch as dropped if it can't take effect
+    pub(crate) fn commit_or_drop(
+        &mut self,
+        len: u32,
+        file_id: FileId,
+        end: u64,
+        recovery_report: &mut RecoveryReport,
+    ) -> Option<Vec<T>> {
+        let batch_offset = self.offset;
+
+        let records = self.commit(len);
+
+        if let (None, Some(batch_offset)) = (&records, batch_offset) {
+            recovery_report.drop_batch(file_id, batch_offset, end);
+        }
+
+        records
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// where the batch starts, if there is one
+    pub(crate) fn offset(&self) -> Option<u64> {
+        self.offset
+    }
 }
 
+/// Returns the latest entry for every key in the given files, deletes included,
+/// and the largest `TxId` of any record read, which can be a record
+/// that was superseded, or that commits a batch.
 pub(crate) async fn load_latest_entries<K, L>(
     db_directory: &Path,
     db_file_ids: &[FileId],
-) -> crate::Result<HashMap<K, L>>
+    encoding: &Encoding,
+    recovery_mode: RecoveryMode,
+    recovery_report: &mut RecoveryReport,
+) -> crate::Result<(HashMap<K, L>, Option<TxId>)>
 where
     K: Eq + Hash,
     L: Loadable<K>,
 {
     let mut all_files_entries: Vec<HashMap<K, L>> = vec![];
 
+    let mut latest_tx_id = None;
+
     // TODO parallelize this
     for file_id in db_file_ids {
-        let file_entries = load_all_entries_from_file(db_directory, *file_id).await?;
+        let (file_entries, file_latest_tx_id) = load_all_entries_from_file(
+            db_directory,
+            *file_id,
+            encoding,
+            recovery_mode,
+            recovery_report,
+        )
+        .await?;
         all_files_entries.push(file_entries);
+        latest_tx_id = latest_tx_id.max(file_latest_tx_id);
     }
 
     let mut all_entries: HashMap<K, L> = HashMap::new();
@@ -45,13 +200,16 @@ where
         }
     }
 
-    Ok(all_entries)
+    Ok((all_entries, latest_tx_id))
 }
 
 async fn load_all_entries_from_file<K, L>(
     db_directory: &Path,
     file_id: FileId,
-) -> crate::Result<HashMap<K, L>>
+    encoding: &Encoding,
+    recovery_mode: RecoveryMode,
+    recovery_report: &mut RecoveryReport,
+) -> crate::Result<(HashMap<K, L>, Option<TxId>)>
 where
     K: Eq + Hash,
     L: Loadable<K>,
@@ -60,19 +218,188 @@ where
 
     path.push(file_id.to_string());
 
-    let f = tokio::fs::File::open(path).await?;
+    let f = tokio::fs::File::open(&path).await?;
 
     let mut reader = tokio::io::BufReader::new(f);
 
     let mut entries = HashMap::new();
 
+    let mut latest_tx_id = None;
+
     let mut offset = 0;
 
+    // the records of an older data file are read as the version it was written in
+    let mut encoding = encoding.clone();
+
+    // a header that was cut short is recovered from like a record that was
+    let mut header_error = match FileHeader::read_from(&mut reader, &path).await {
+        Ok(Some(header)) => {
+            header.check(&encoding, &path)?;
+            encoding = encoding.for_file(&header);
+            offset = header.records_start();
+            None
+        }
+        Ok(None) => return Ok((entries, latest_tx_id)),
+        Err(crate::error::Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Some(e.into())
+        }
+        Err(e) => return Err(e),
+    };
+
+    // a hint file that is missing, torn, or corrupt is not fatal,
+    // as the data file it describes is always the source of truth
+    if header_error.is_none() {
+        match load_all_entries_from_hint_file(db_directory, file_id, &encoding).await {
+            Ok(Some(loaded)) => return Ok(loaded),
+            Ok(None) | Err(crate::error::Error::CorruptRecord) => (),
+            Err(crate::error::Error::IoError(e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut pending_batch = PendingBatch::new();
+
+    loop {
+        let record_offset = offset;
+
+        let read = match header_error.take() {
+            Some(e) => Err(e),
+            None => L::read_one(&mut reader, &mut offset, file_id, &encoding).await,
+        };
+
+        let e = match read {
+            Ok(Some(Loaded::Entry(k, entry_with_liveness))) => {
+                latest_tx_id = latest_tx_id.max(Some(entry_with_liveness.tx_id()));
+                pending_batch.drop_uncommitted(file_id, record_offset, recovery_report);
+                entries.insert(k, entry_with_liveness);
+                continue;
+            }
+            Ok(Some(Loaded::BatchEntry(k, entry_with_liveness))) => {
+                latest_tx_id = latest_tx_id.max(Some(entry_with_liveness.tx_id()));
+                pending_batch.push(record_offset, (k, entry_with_liveness));
+                continue;
+            }
+            Ok(Some(Loaded::BatchCommit(len, tx_id))) => {
+                latest_tx_id = latest_tx_id.max(Some(tx_id));
+                if let Some(records) =
+                    pending_batch.commit_or_drop(len, file_id, offset, recovery_report)
+                {
+                    entries.extend(records);
+                }
+                continue;
+            }
+            Ok(None) => {
+                if !pending_batch.is_empty() {
+                    std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "batch was never committed",
+                    )
+                    .into()
+                } else {
+                    break;
+                }
+            }
+            Err(e) => e,
+        };
+
+        let is_torn = matches!(
+            &e,
+            crate::error::Error::IoError(io_error)
+                if io_error.kind() == std::io::ErrorKind::UnexpectedEof
+        );
+
+        let is_corrupt = matches!(e, crate::error::Error::CorruptRecord);
+
+        if !is_torn && !is_corrupt {
+            return Err(e);
+        }
+
+        match recovery_mode {
+            RecoveryMode::Strict => return Err(e),
+            RecoveryMode::TruncateTail => {
+                // an uncommitted batch is as torn as the record that follows it
+                let truncate_offset = pending_batch.offset().unwrap_or(record_offset);
+
+                let len = crate::recovery::data_file_len(db_directory, file_id).await?;
+
+                let quarantined_to =
+                    crate::recovery::quarantine_tail(db_directory, file_id, truncate_offset)
+                        .await?;
+
+                recovery_report.dropped.push(DroppedRange {
+                    file_id,
+                    offset: truncate_offset,
+                    len: len - truncate_offset,
+                    quarantined_to: Some(quarantined_to),
+                });
+
+                break;
+            }
+            RecoveryMode::SkipCorrupt => {
+                // a torn record is always the last thing in a file,
+                // but a corrupt record that was read in full can be skipped over
+                if is_torn {
+                    let len = crate::recovery::data_file_len(db_directory, file_id).await?;
+
+                    match pending_batch.offset() {
+                        Some(batch_offset) => {
+                            recovery_report.drop_batch(file_id, batch_offset, len);
+                        }
+                        None => recovery_report.dropped.push(DroppedRange {
+                            file_id,
+                            offset: record_offset,
+                            len: len - record_offset,
+                            quarantined_to: None,
+                        }),
+                    }
+
+                    break;
+                } else {
+                    offset = reader.stream_position().await?;
+
+                    recovery_report.dropped.push(DroppedRange {
+                        file_id,
+                        offset: record_offset,
+                        len: offset - record_offset,
+                        quarantined_to: None,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok((entries, latest_tx_id))
+}
+
+/// returns `Ok(None)` if the data file has no hint file
+async fn load_all_entries_from_hint_file<K, L>(
+    db_directory: &Path,
+    file_id: FileId,
+    encoding: &Encoding,
+) -> crate::Result<Option<(HashMap<K, L>, Option<TxId>)>>
+where
+    K: Eq + Hash,
+    L: Loadable<K>,
+{
+    let f = match tokio::fs::File::open(Hint::path(db_directory, file_id)).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut reader = tokio::io::BufReader::new(f);
+
+    let mut entries = HashMap::new();
+
+    let mut latest_tx_id = None;
+
     while let Some((k, entry_with_liveness)) =
-        L::read_one(&mut reader, &mut offset, file_id).await?
+        L::read_one_hint(&mut reader, file_id, encoding).await?
     {
+        latest_tx_id = latest_tx_id.max(Some(entry_with_liveness.tx_id()));
         entries.insert(k, entry_with_liveness);
     }
 
-    Ok(entries)
+    Ok(Some((entries, latest_tx_id)))
 }
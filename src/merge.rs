@@ -0,0 +1,293 @@
+use crate::file_stats::FileStats;
+use crate::keydir::FileId;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+/// A merge running in the background, as started by `B2::merge_in_background`.
+///
+/// Await it to get the result of the merge.
+/// Dropping it does not stop the merge; use `cancel` for that.
+#[derive(Debug)]
+pub struct MergeHandle {
+    task: tokio::task::JoinHandle<crate::Result<()>>,
+    control: Arc<MergeControl>,
+}
+
+impl MergeHandle {
+    pub(crate) fn new(
+        task: tokio::task::JoinHandle<crate::Result<()>>,
+        control: Arc<MergeControl>,
+    ) -> Self {
+        Self { task, control }
+    }
+
+    /// Ask the merge to stop. A merge that is cancelled before it swaps
+    /// its merged files in leaves the database as it was,
+    /// and resolves to `Error::MergeCancelled`.
+    /// A merge that has already started swapping its files in runs to completion.
+    pub fn cancel(&self) {
+        self.control.cancel();
+    }
+
+    /// How far along the merge is.
+    pub fn progress(&self) -> MergeProgress {
+        self.control.progress()
+    }
+
+    /// True if the merge has finished, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Future for MergeHandle {
+    type Output = crate::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.task).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(join_error)) => {
+                if join_error.is_panic() {
+                    std::panic::resume_unwind(join_error.into_panic())
+                } else {
+                    Poll::Ready(Err(crate::error::Error::MergeCancelled))
+                }
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// How far along a merge is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeProgress {
+    /// the number of keys in the files being merged.
+    /// this is 0 until the files being merged have been read.
+    pub keys_total: u64,
+    /// the number of those keys that have been either copied or skipped
+    pub keys_processed: u64,
+}
+
+/// shared between a merge and whatever started it
+#[derive(Debug, Default)]
+pub(crate) struct MergeControl {
+    cancelled: AtomicBool,
+    keys_total: AtomicU64,
+    keys_processed: AtomicU64,
+}
+
+impl MergeControl {
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_keys_total(&self, keys_total: u64) {
+        self.keys_total.store(keys_total, Ordering::Release);
+    }
+
+    pub(crate) fn key_processed(&self) {
+        self.keys_processed.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn progress(&self) -> MergeProgress {
+        MergeProgress {
+            keys_total: self.keys_total.load(Ordering::Acquire),
+            keys_processed: self.keys_processed.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// When the database merges on its own, as set in `Options::merge_policy`.
+///
+/// Every `check_interval`, if the current time is in one of the `windows`,
+/// the database looks at the `FileStats` of each inactive file.
+/// A file crosses the thresholds when at least `dead_bytes_ratio` of it
+/// *and* at least `min_dead_bytes` of it are dead.
+/// Once at least `min_files` inactive files cross the thresholds,
+/// those files, and only those files, are merged in the background.
+///
+/// A merge of only some of the inactive files keeps the deletes it finds,
+/// as the records they delete may be in files it did not merge.
+/// A merge of every inactive file (like `B2::merge`) drops them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergePolicy {
+    /// defaults to 0.5
+    pub dead_bytes_ratio: f64,
+    /// defaults to 1 MiB
+    pub min_dead_bytes: u64,
+    /// defaults to 1
+    pub min_files: usize,
+    /// when merges may start. defaults to empty, meaning at any time
+    pub windows: Vec<MergeWindow>,
+    /// defaults to 60 seconds
+    pub check_interval: Duration,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self {
+            dead_bytes_ratio: 0.5,
+            min_dead_bytes: 2u64.pow(20),
+            min_files: 1,
+            windows: vec![],
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl MergePolicy {
+    /// true if the given file crosses the thresholds
+    pub(crate) fn selects(&self, stats: &FileStats) -> bool {
+        stats.dead_bytes > 0
+            && stats.dead_bytes >= self.min_dead_bytes
+            && stats.dead_bytes_ratio() >= self.dead_bytes_ratio
+    }
+
+    /// true if a merge may start at the given time
+    pub(crate) fn allows(&self, now: SystemTime) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+
+        let seconds = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let hour = ((seconds / 3600) % 24) as u8;
+
+        self.windows.iter().any(|window| window.contains_hour(hour))
+    }
+}
+
+/// A span of hours of the day, in UTC, during which a merge may start.
+/// `start_hour` is inclusive and `end_hour` is exclusive,
+/// so `MergeWindow { start_hour: 22, end_hour: 4 }` runs from 22:00 to 03:59.
+/// A window whose start and end are the same covers the whole day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeWindow {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl MergeWindow {
+    pub fn contains_hour(&self, hour: u8) -> bool {
+        match self.start_hour.cmp(&self.end_hour) {
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Less => self.start_hour <= hour && hour < self.end_hour,
+            std::cmp::Ordering::Greater => self.start_hour <= hour || hour < self.end_hour,
+        }
+    }
+}
+
+/// which inactive files a merge merges
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum MergeSelection<'a> {
+    All,
+    Files(&'a [FileId]),
+    Policy(&'a MergePolicy),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::wait_until;
+    use crate::{Options, B2};
+
+    #[tokio::test]
+    async fn merge_policy_merges_only_files_over_the_thresholds() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let big = "x".repeat(1000);
+
+        // file 1 is mostly live, and has the record that file 2 deletes
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("deleted".to_string(), 1).await.unwrap();
+        db.insert("live".to_string(), big.clone()).await.unwrap();
+        drop(db);
+
+        // file 2 is mostly dead
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        db.insert("overwritten".to_string(), big.clone())
+            .await
+            .unwrap();
+        db.insert("overwritten".to_string(), big.clone())
+            .await
+            .unwrap();
+        db.insert("overwritten".to_string(), 2).await.unwrap();
+        db.remove("deleted".to_string()).await.unwrap();
+        drop(db);
+
+        let mut file_1 = dir.path().to_owned();
+        file_1.push("1");
+        let file_1_before = tokio::fs::read(&file_1).await.unwrap();
+
+        let options = Options {
+            merge_policy: Some(MergePolicy {
+                dead_bytes_ratio: 0.5,
+                min_dead_bytes: 1,
+                check_interval: std::time::Duration::from_millis(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options).await.unwrap();
+
+        let stats = db.file_stats().await;
+        assert!(stats[0].dead_bytes_ratio() < 0.5);
+        assert!(stats[1].dead_bytes_ratio() > 0.5);
+
+        let file_2_len_before = stats[1].total_bytes;
+
+        wait_until("the merge policy never merged", async || {
+            db.file_stats().await[1].total_bytes != file_2_len_before
+        })
+        .await;
+
+        assert_eq!(db.file_stats().await[1].dead_bytes, 0);
+        assert_eq!(tokio::fs::read(&file_1).await.unwrap(), file_1_before);
+
+        db.close().await.unwrap();
+
+        // the delete in file 2 was kept, as file 1 was not merged
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+        assert_eq!(db.get::<i32>(&"deleted".to_string()).await.unwrap(), None);
+        assert_eq!(
+            db.get::<i32>(&"overwritten".to_string()).await.unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            db.get::<String>(&"live".to_string()).await.unwrap(),
+            Some(big)
+        );
+    }
+
+    #[test]
+    fn merge_windows_wrap_past_midnight() {
+        let window = MergeWindow {
+            start_hour: 22,
+            end_hour: 4,
+        };
+
+        assert!(window.contains_hour(23));
+        assert!(window.contains_hour(0));
+        assert!(!window.contains_hour(4));
+        assert!(!window.contains_hour(12));
+
+        let all_day = MergeWindow {
+            start_hour: 3,
+            end_hour: 3,
+        };
+
+        assert!((0..24).all(|hour| all_day.contains_hour(hour)));
+    }
+}
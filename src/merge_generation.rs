@@ -0,0 +1,44 @@
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+/// How many times merged files have been swapped in, which is how
+/// a follower tells that the files it has read have been merged.
+///
+/// `MergeManifest::apply` bumps it before it swaps any files in,
+/// so a follower that sees a merged file also sees the merge's generation.
+/// A directory without one has never had a merge swapped in.
+///
+/// The generation is a small text file holding a count in decimal.
+pub(crate) struct MergeGeneration;
+
+impl MergeGeneration {
+    const FILE_NAME: &'static str = "MERGES";
+    const TEMP_FILE_NAME: &'static str = "MERGES.tmp";
+
+    pub(crate) async fn read(db_directory: &Path) -> crate::Result<u64> {
+        let contents = match tokio::fs::read_to_string(db_directory.join(Self::FILE_NAME)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(contents.trim().parse()?)
+    }
+
+    /// durably move on to the next generation
+    pub(crate) async fn bump(db_directory: &Path) -> crate::Result<()> {
+        let generation = Self::read(db_directory).await? + 1;
+
+        let temp_path = db_directory.join(Self::TEMP_FILE_NAME);
+
+        let mut f = tokio::fs::File::create(&temp_path).await?;
+
+        f.write_all(format!("{generation}\n").as_bytes()).await?;
+
+        f.sync_all().await?;
+
+        tokio::fs::rename(temp_path, db_directory.join(Self::FILE_NAME)).await?;
+
+        crate::base::sync_directory(db_directory).await
+    }
+}
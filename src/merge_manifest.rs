@@ -0,0 +1,194 @@
+use crate::hint::Hint;
+use crate::keydir::FileId;
+use crate::merge_generation::MergeGeneration;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// The record of a merge that has been committed,
+/// but whose files may not have all been swapped in yet.
+///
+/// A merge commits like this:
+/// 1. write the merged data and hint files as `N.merge` and `N.hint.merge`,
+///    and sync them and the directory
+/// 2. write the manifest to a temporary file, sync it,
+///    rename it into place and sync the directory.
+///    this is the point at which the merge happens or does not
+/// 3. swap the merged files in, as `apply` does
+/// 4. remove the manifest and sync the directory
+///
+/// If the process dies before step 2 finishes, opening the database
+/// removes the `.merge` files, rolling the merge back.
+/// If it dies after, opening the database does steps 3 and 4, rolling the merge forward.
+///
+/// The manifest is a small text file:
+/// ```text
+/// inputs 1 2 3
+/// outputs 2 3
+/// ```
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MergeManifest {
+    /// the data files that were merged
+    pub(crate) inputs: Vec<FileId>,
+    /// the merged data files, which reuse the ids of some of the inputs
+    pub(crate) outputs: Vec<FileId>,
+}
+
+impl MergeManifest {
+    const FILE_NAME: &'static str = "merge.manifest";
+    const TEMP_FILE_NAME: &'static str = "merge.manifest.tmp";
+
+    /// durably write the manifest, committing the merge it describes
+    pub(crate) async fn write(&self, db_directory: &Path) -> crate::Result<()> {
+        let temp_path = db_directory.join(Self::TEMP_FILE_NAME);
+
+        let mut f = tokio::fs::File::create(&temp_path).await?;
+
+        f.write_all(self.to_string().as_bytes()).await?;
+
+        f.sync_all().await?;
+
+        tokio::fs::rename(temp_path, db_directory.join(Self::FILE_NAME)).await?;
+
+        crate::base::sync_directory(db_directory).await
+    }
+
+    /// returns `Ok(None)` if there is no committed merge to finish
+    pub(crate) async fn read(db_directory: &Path) -> crate::Result<Option<Self>> {
+        let contents = match tokio::fs::read_to_string(db_directory.join(Self::FILE_NAME)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lines = contents.lines();
+
+        let inputs = Self::parse_line(lines.next(), "inputs")?;
+        let outputs = Self::parse_line(lines.next(), "outputs")?;
+
+        Ok(Some(Self { inputs, outputs }))
+    }
+
+    /// swap the merged files in for the files they replace.
+    /// this can be interrupted and run again any number of times.
+    pub(crate) async fn apply(&self, db_directory: &Path) -> crate::Result<()> {
+        // before anything is swapped in, so that followers load everything again
+        MergeGeneration::bump(db_directory).await?;
+
+        for file_id in &self.inputs {
+            if !self.outputs.contains(file_id) {
+                remove_if_exists(Hint::path(db_directory, *file_id)).await?;
+                remove_if_exists(db_directory.join(file_id.to_string())).await?;
+            }
+        }
+
+        for file_id in &self.outputs {
+            let merged_data_path = db_directory.join(format!("{}.merge", **file_id));
+
+            // if it is gone, it has already been renamed
+            if tokio::fs::try_exists(&merged_data_path).await? {
+                // the old hint goes first, so that it never describes the new data file
+                remove_if_exists(Hint::path(db_directory, *file_id)).await?;
+
+                tokio::fs::rename(merged_data_path, db_directory.join(file_id.to_string())).await?;
+            }
+
+            let merged_hint_path =
+                db_directory.join(format!("{}.{}.merge", **file_id, Hint::FILE_EXTENSION));
+
+            if tokio::fs::try_exists(&merged_hint_path).await? {
+                tokio::fs::rename(merged_hint_path, Hint::path(db_directory, *file_id)).await?;
+            }
+        }
+
+        crate::base::sync_directory(db_directory).await
+    }
+
+    /// the merge is finished once its manifest is gone
+    pub(crate) async fn remove(db_directory: &Path) -> crate::Result<()> {
+        remove_if_exists(db_directory.join(Self::FILE_NAME)).await?;
+        crate::base::sync_directory(db_directory).await
+    }
+
+    fn parse_line(line: Option<&str>, name: &str) -> crate::Result<Vec<FileId>> {
+        let mut words = line
+            .ok_or(crate::error::Error::CorruptMergeManifest)?
+            .split_whitespace();
+
+        if words.next() != Some(name) {
+            return Err(crate::error::Error::CorruptMergeManifest);
+        }
+
+        words
+            .map(|word| word.parse::<FileId>().map_err(|e| e.into()))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for MergeManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "inputs")?;
+        for file_id in &self.inputs {
+            write!(f, " {}", **file_id)?;
+        }
+        writeln!(f)?;
+
+        write!(f, "outputs")?;
+        for file_id in &self.outputs {
+            write!(f, " {}", **file_id)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Finishes a merge that the process died in the middle of, if there is one,
+/// rolling it forward if it was committed and back if it was not.
+pub(crate) async fn finish_interrupted_merge(db_directory: &Path) -> crate::Result<()> {
+    if let Some(manifest) = MergeManifest::read(db_directory).await? {
+        manifest.apply(db_directory).await?;
+        MergeManifest::remove(db_directory).await?;
+    }
+
+    remove_if_exists(db_directory.join(MergeManifest::TEMP_FILE_NAME)).await?;
+
+    let leftover_merge_files = merge_file_paths(db_directory).await?;
+
+    if !leftover_merge_files.is_empty() {
+        for merge_path in leftover_merge_files {
+            tokio::fs::remove_file(merge_path).await?;
+        }
+
+        crate::base::sync_directory(db_directory).await?;
+    }
+
+    Ok(())
+}
+
+/// every `.merge` file in the given directory
+pub(crate) async fn merge_file_paths(db_directory: &Path) -> crate::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+
+    let mut dir_reader = tokio::fs::read_dir(db_directory).await?;
+
+    while let Some(dir_entry) = dir_reader.next_entry().await? {
+        if dir_entry.file_type().await?.is_file() {
+            let path = dir_entry.path();
+
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "merge")
+            {
+                paths.push(path);
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+async fn remove_if_exists(path: PathBuf) -> crate::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
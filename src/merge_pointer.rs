@@ -1,6 +1,7 @@
+use crate::encoding::Encoding;
 use crate::keydir::{FileId, Liveness};
-use crate::loadable::Loadable;
-use crate::record::{KeySize, TxId, ValueSize};
+use crate::loadable::{Loadable, Loaded};
+use crate::record::{ExpiresAt, KeySize, RecordKind, Timestamp, TxId, ValueSize};
 use serde::de::DeserializeOwned;
 use std::hash::Hash;
 use tokio::io::AsyncRead;
@@ -12,10 +13,14 @@ pub(crate) struct MergePointer {
     pub(crate) liveness: Liveness,
     pub(crate) file_id: FileId,
     pub(crate) tx_id: TxId,
+    pub(crate) timestamp: Timestamp,
+    pub(crate) expires_at: ExpiresAt,
     pub(crate) record_offset: u64,
     pub(crate) record_size: u64,
     pub(crate) key_size: KeySize,
     pub(crate) value_size: ValueSize,
+    /// the `FileHeader` version of the data file
+    pub(crate) format_version: u16,
 }
 
 impl PartialOrd for MergePointer {
@@ -32,23 +37,32 @@ where
         reader: &mut tokio::io::BufReader<R>,
         offset: &mut u64,
         file_id: FileId,
-    ) -> crate::Result<Option<(K, Self)>> {
-        let record = match crate::record::Record::read_from(reader).await {
-            Ok(record) => record,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    return Ok(None);
-                } else {
-                    return Err(e.into());
-                }
-            }
+        encoding: &Encoding,
+    ) -> crate::Result<Option<Loaded<K, Self>>> {
+        let record = match crate::record::Record::read_from(
+            reader,
+            encoding.size_fields,
+            encoding.format_version,
+        )
+        .await?
+        {
+            Some(record) => record,
+            None => return Ok(None),
         };
 
         if !record.is_valid() {
             return Err(crate::error::Error::CorruptRecord);
         }
 
-        let key = record.key()?;
+        if record.kind() == RecordKind::BatchCommit {
+            *offset += record.len() as u64;
+            return Ok(Some(Loaded::BatchCommit(
+                record.batch_len(),
+                record.tx_id(),
+            )));
+        }
+
+        let key = record.key(encoding)?;
 
         let liveness = record.liveness();
 
@@ -56,14 +70,68 @@ where
             liveness,
             file_id,
             tx_id: record.tx_id(),
+            timestamp: record.timestamp(),
+            expires_at: record.expires_at(),
             record_offset: *offset,
             record_size: record.len() as u64,
             key_size: record.key_size(),
             value_size: record.value_size(),
+            format_version: encoding.format_version,
         };
 
         *offset += record.len() as u64;
 
+        Ok(Some(Loaded::new(record.kind(), key, out)))
+    }
+
+    async fn read_one_hint<R: AsyncRead + Unpin>(
+        reader: &mut tokio::io::BufReader<R>,
+        file_id: FileId,
+        encoding: &Encoding,
+    ) -> crate::Result<Option<(K, Self)>> {
+        let hint = match crate::hint::Hint::read_from(reader, encoding.size_fields).await? {
+            Some(hint) => hint,
+            None => return Ok(None),
+        };
+
+        if !hint.is_valid() {
+            return Err(crate::error::Error::CorruptRecord);
+        }
+
+        let key = hint.key(encoding)?;
+
+        let key_size = hint.key_size();
+        let value_size = hint.value_size();
+
+        let record_offset = hint.value_position()
+            - crate::record::Record::header_size_for(encoding.size_fields, encoding.format_version)
+                as u64
+            - u64::from(key_size.0);
+
+        let record_size = crate::record::Record::len_for(
+            encoding.size_fields,
+            encoding.format_version,
+            key_size.0.into(),
+            &value_size,
+        );
+
+        let out = MergePointer {
+            liveness: hint.liveness(),
+            file_id,
+            tx_id: hint.tx_id(),
+            timestamp: hint.timestamp(),
+            expires_at: hint.expires_at(),
+            record_offset,
+            record_size,
+            key_size,
+            value_size,
+            format_version: encoding.format_version,
+        };
+
         Ok(Some((key, out)))
     }
+
+    fn tx_id(&self) -> TxId {
+        self.tx_id
+    }
 }
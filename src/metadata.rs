@@ -0,0 +1,30 @@
+use crate::keydir::{EntryPointer, FileId};
+use std::time::SystemTime;
+
+/// What the database knows about a key's current value,
+/// without reading the value itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// the transaction that wrote the value. Later writes have larger `tx_id`s
+    pub tx_id: u128,
+    /// when the value was written, according to the writer's clock
+    pub timestamp: SystemTime,
+    /// `None` if the value never expires
+    pub expires_at: Option<SystemTime>,
+    /// the size of the serialized value as it is stored, after any compression, in bytes
+    pub value_size: u64,
+    /// the data file that holds the value
+    pub file_id: FileId,
+}
+
+impl From<&EntryPointer> for Metadata {
+    fn from(entry: &EntryPointer) -> Self {
+        Self {
+            tx_id: *entry.tx_id,
+            timestamp: entry.timestamp.to_system_time(),
+            expires_at: entry.expires_at.to_system_time(),
+            value_size: entry.value_size.0,
+            file_id: entry.file_id,
+        }
+    }
+}
@@ -1,25 +1,42 @@
-use crate::{error, keydir::Liveness};
-use serde::{de::DeserializeOwned, Serialize};
+use crate::codec::Codec;
+use crate::compression::{Compression, CompressionKind};
+use crate::encoding::Encoding;
+use crate::file_header::FileHeader;
+use crate::keydir::Liveness;
+use serde::de::DeserializeOwned;
 use std::ops::{Add, AddAssign};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{ops::Deref, sync::OnceLock};
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt};
 
-const TOMBSTONE_BYTES: &[u8] = b"bitcask_tombstone";
+/// what version 0 and 1 data files stored as the value of a delete. see `Record::legacy_tombstone`
+const LEGACY_TOMBSTONE_BYTES: &[u8] = b"bitcask_tombstone";
 
-static SERIALIZED_TOMBSTONE: OnceLock<Vec<u8>> = OnceLock::new();
+static SERIALIZED_LEGACY_TOMBSTONE: OnceLock<Vec<u8>> = OnceLock::new();
 
 /// A record is a "header" and a "body"
 /// The header is (in on-disk and in-memory order):
 /// - hash (the paper calls this `crc`) (4 bytes)
-/// - tx_id (the paper calls this `tstamp`) (16 bytes)
-/// - key_size (4 bytes)
-/// - value_size (4 bytes)
+/// - kind (1 byte): the `RecordKind` in the low 4 bits,
+///   the `CompressionKind` of the value in the next 2,
+///   and whether the record is a delete in the next 1
+/// - tx_id (16 bytes)
+/// - timestamp (the paper calls this `tstamp`) (8 bytes)
+/// - expires_at (8 bytes)
+/// - key_size (2 bytes, or 4 with `SizeFields::Wide`)
+/// - value_size (4 bytes, or 8 with `SizeFields::Wide`)
 ///
 /// The body is (also in on-disk and in-memory order):
 /// - key
-/// - value
+/// - value, which is empty for a delete
+///
+/// Records in version 0 data files, which have no `FileHeader`,
+/// only have the hash, tx_id, and compact key_size and value_size in their header.
 pub(crate) struct Record {
     buf: Vec<u8>,
+    size_fields: SizeFields,
+    /// the `FileHeader` version of the data file the record was read from
+    format_version: u16,
 }
 
 impl Deref for Record {
@@ -30,91 +47,445 @@ impl Deref for Record {
     }
 }
 
-// crate-public impls
-impl Record {
-    pub(crate) const HEADER_SIZE: usize =
-        Record::HASH_SIZE + Record::TX_ID_SIZE + Record::KEY_SIZE_SIZE + Record::VALUE_SIZE_SIZE;
+/// What a record is for.
+///
+/// A batch is written as some number of `Batched` records,
+/// followed by a `BatchCommit` record that says how many there were.
+/// `Batched` records only take effect if their `BatchCommit` made it to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum RecordKind {
+    /// an insert or delete that takes effect on its own
+    Standalone = 0,
+    /// an insert or delete that is part of a batch
+    Batched = 1,
+    /// the end of a batch.
+    /// it has no key, and its value is the number of records in the batch
+    BatchCommit = 2,
+}
 
-    pub(crate) fn new<K: Serialize, V: Serialize>(
-        k: &K,
-        v: &V,
-        tx_id: TxId,
-    ) -> crate::Result<Self> {
-        let encoded_tx_id = tx_id.to_be_bytes();
+impl TryFrom<u8> for RecordKind {
+    type Error = u8;
 
-        let encoded_key = bincode::serialize(k).map_err(|e| error::SerializeError {
-            msg: "unable to serialize to bincode".to_string(),
-            source: e,
-        })?;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(RecordKind::Standalone),
+            1 => Ok(RecordKind::Batched),
+            2 => Ok(RecordKind::BatchCommit),
+            other => Err(other),
+        }
+    }
+}
 
-        let encoded_value = bincode::serialize(v).map_err(|e| error::SerializeError {
-            msg: "unable to serialize to bincode".to_string(),
-            source: e,
-        })?;
+/// How wide the key size and value size fields of records are,
+/// which limits how large keys and values can be. Set with `Options::size_fields`.
+///
+/// A database uses the same for all of its data files,
+/// and each data file's header records it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SizeFields {
+    /// 2-byte key sizes and 4-byte value sizes,
+    /// for keys up to 64 KiB and values up to 4 GiB
+    #[default]
+    Compact,
+    /// 4-byte key sizes and 8-byte value sizes,
+    /// for keys up to 4 GiB and values as large as will fit on disk,
+    /// at 6 more bytes per record
+    Wide,
+}
 
-        let key_size = encoded_key.len();
-        let value_size = encoded_value.len();
-        let body_size = key_size + value_size;
+impl SizeFields {
+    /// the size of the largest key, as stored, that a record can hold
+    pub fn max_key_size(self) -> u64 {
+        match self {
+            SizeFields::Compact => u16::MAX.into(),
+            SizeFields::Wide => u32::MAX.into(),
+        }
+    }
 
-        let encoded_key_size = KeySize(key_size as u16).0.to_be_bytes();
-        let encoded_value_size = ValueSize(value_size as u32).0.to_be_bytes();
+    /// the size of the largest value, as stored, that a record can hold
+    pub fn max_value_size(self) -> u64 {
+        match self {
+            SizeFields::Compact => u32::MAX.into(),
+            SizeFields::Wide => u64::MAX,
+        }
+    }
 
-        let mut buf = Vec::with_capacity(Self::HEADER_SIZE + body_size);
-        // header
-        // dummy hash bytes, added back in at the end...
-        buf.extend_from_slice(&[0u8; Self::HASH_SIZE]);
-        // rest of header
-        buf.extend_from_slice(&encoded_tx_id);
-        buf.extend_from_slice(&encoded_key_size);
-        buf.extend_from_slice(&encoded_value_size);
-        // body
-        buf.extend_from_slice(&encoded_key);
-        buf.extend_from_slice(&encoded_value);
+    pub(crate) fn key_size_size(self) -> usize {
+        match self {
+            SizeFields::Compact => std::mem::size_of::<u16>(),
+            SizeFields::Wide => std::mem::size_of::<u32>(),
+        }
+    }
 
-        let hash = crc32fast::hash(&buf[Self::HASH_SIZE..]);
-        let hash_bytes = hash.to_be_bytes();
-        // ...and finally set the first HASH_SIZE bytes to the hash
-        buf[..Self::HASH_SIZE].copy_from_slice(&hash_bytes);
+    pub(crate) fn value_size_size(self) -> usize {
+        match self {
+            SizeFields::Compact => std::mem::size_of::<u32>(),
+            SizeFields::Wide => std::mem::size_of::<u64>(),
+        }
+    }
+
+    /// a key size, as it is stored
+    pub(crate) fn encode_key_size(self, key_size: usize) -> Vec<u8> {
+        match self {
+            SizeFields::Compact => u16::try_from(key_size)
+                .expect("keys are checked against the limit before they are written")
+                .to_be_bytes()
+                .to_vec(),
+            SizeFields::Wide => u32::try_from(key_size)
+                .expect("keys are checked against the limit before they are written")
+                .to_be_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// a value size, as it is stored
+    pub(crate) fn encode_value_size(self, value_size: usize) -> Vec<u8> {
+        match self {
+            SizeFields::Compact => u32::try_from(value_size)
+                .expect("values are checked against the limit before they are written")
+                .to_be_bytes()
+                .to_vec(),
+            SizeFields::Wide => (value_size as u64).to_be_bytes().to_vec(),
+        }
+    }
+
+    pub(crate) fn decode_key_size(self, bytes: &[u8]) -> KeySize {
+        match self {
+            SizeFields::Compact => KeySize(u16::from_be_bytes(bytes.try_into().unwrap()).into()),
+            SizeFields::Wide => KeySize(u32::from_be_bytes(bytes.try_into().unwrap())),
+        }
+    }
+
+    pub(crate) fn decode_value_size(self, bytes: &[u8]) -> ValueSize {
+        match self {
+            SizeFields::Compact => ValueSize(u32::from_be_bytes(bytes.try_into().unwrap()).into()),
+            SizeFields::Wide => ValueSize(u64::from_be_bytes(bytes.try_into().unwrap())),
+        }
+    }
+}
 
-        Ok(Record { buf })
+// crate-public impls
+impl Record {
+    pub(crate) fn header_size(size_fields: SizeFields) -> usize {
+        Self::HASH_SIZE
+            + Self::KIND_SIZE
+            + Self::TX_ID_SIZE
+            + Self::TIMESTAMP_SIZE
+            + Self::EXPIRES_AT_SIZE
+            + size_fields.key_size_size()
+            + size_fields.value_size_size()
+    }
+
+    /// the header size of the records in a data file of the given format version
+    pub(crate) fn header_size_for(size_fields: SizeFields, format_version: u16) -> usize {
+        if format_version == FileHeader::LEGACY_VERSION {
+            Self::LEGACY_HEADER_SIZE
+        } else {
+            Self::header_size(size_fields)
+        }
+    }
+
+    /// build a record from a key and value that have already been serialized,
+    /// and the value compressed with `compression`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn from_encoded(
+        size_fields: SizeFields,
+        kind: RecordKind,
+        compression: CompressionKind,
+        tx_id: TxId,
+        timestamp: Timestamp,
+        expires_at: ExpiresAt,
+        encoded_key: &[u8],
+        encoded_value: &[u8],
+    ) -> Self {
+        Self::build(
+            size_fields,
+            Self::kind_byte(kind, compression),
+            tx_id,
+            timestamp,
+            expires_at,
+            encoded_key,
+            encoded_value,
+        )
+    }
+
+    /// the record that deletes the key
+    pub(crate) fn delete(
+        size_fields: SizeFields,
+        kind: RecordKind,
+        tx_id: TxId,
+        timestamp: Timestamp,
+        encoded_key: &[u8],
+    ) -> Self {
+        Self::build(
+            size_fields,
+            kind as u8 | Self::DELETE,
+            tx_id,
+            timestamp,
+            ExpiresAt::NEVER,
+            encoded_key,
+            &[],
+        )
+    }
+
+    /// the record that commits the `len` records before it
+    pub(crate) fn batch_commit(
+        size_fields: SizeFields,
+        tx_id: TxId,
+        timestamp: Timestamp,
+        len: u32,
+    ) -> Self {
+        Self::from_encoded(
+            size_fields,
+            RecordKind::BatchCommit,
+            CompressionKind::None,
+            tx_id,
+            timestamp,
+            ExpiresAt::NEVER,
+            &[],
+            &len.to_be_bytes(),
+        )
     }
 
+    /// Returns `Ok(None)` if the reader is cleanly at the end of the file,
+    /// and an `UnexpectedEof` error if a record is only partially present,
+    /// as happens when a write is torn by a crash.
+    /// `format_version` is the version in the header of the data file being read.
     pub(crate) async fn read_from<R: AsyncRead + Unpin>(
         reader: &mut tokio::io::BufReader<R>,
-    ) -> std::io::Result<Record> {
-        let buf = vec![0u8; Record::HEADER_SIZE];
+        size_fields: SizeFields,
+        format_version: u16,
+    ) -> std::io::Result<Option<Record>> {
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
 
-        let mut record = Record { buf };
+        let buf = vec![0u8; Record::header_size_for(size_fields, format_version)];
 
-        reader.read_exact(&mut record.buf).await?;
+        let mut record = Record {
+            buf,
+            size_fields,
+            format_version,
+        };
 
-        let key_size_usize: usize = record.key_size().0.into();
-        let value_size_usize: usize = record.value_size().0.try_into().unwrap();
-        let body_size: usize = key_size_usize + value_size_usize;
+        reader.read_exact(&mut record.buf).await?;
 
-        record.buf.resize(record.buf.len() + body_size, 0);
+        let key_size_u64: u64 = record.key_size().0.into();
+        let value_size_u64: u64 = record.value_size().0;
+        let body_size: u64 = key_size_u64.saturating_add(value_size_u64);
 
-        let body = &mut record.buf[Record::HEADER_SIZE..];
+        // the sizes in a torn or corrupt header can be garbage,
+        // so read the body incrementally rather than allocating it up front
+        let bytes_read = (&mut *reader)
+            .take(body_size)
+            .read_to_end(&mut record.buf)
+            .await?;
 
-        reader.read_exact(body).await?;
+        if bytes_read as u64 != body_size {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
 
-        Ok(record)
+        Ok(Some(record))
     }
 
-    pub(crate) fn key<K: DeserializeOwned>(&self) -> Result<K, crate::error::DeserializeError> {
-        bincode::deserialize(self.key_bytes()).map_err(|e| crate::error::DeserializeError {
-            msg: "unable to deserialize from bincode".to_string(),
-            source: e,
-        })
+    pub(crate) fn key<K: DeserializeOwned>(&self, encoding: &Encoding) -> crate::Result<K> {
+        encoding.decode_key(self.key_bytes())
     }
 
     pub(crate) fn is_valid(&self) -> bool {
+        if self.is_legacy() {
+            return self.hash_read_from_disk() == self.computed_hash();
+        }
+
+        let kind_byte = self.header()[Self::HASH_SIZE];
+
+        let Ok(kind) = RecordKind::try_from(kind_byte & Self::KIND_MASK) else {
+            return false;
+        };
+
+        // a record can match its hash and still be malformed
+        if kind == RecordKind::BatchCommit
+            && (!self.key_bytes().is_empty()
+                || self.value_bytes().len() != std::mem::size_of::<u32>())
+        {
+            return false;
+        }
+
+        // a delete has no value, so flagging an insert as one can't go unnoticed
+        if kind_byte & Self::DELETE != 0 && !self.value_bytes().is_empty() {
+            return false;
+        }
+
         self.hash_read_from_disk() == self.computed_hash()
+            && CompressionKind::try_from(
+                (kind_byte >> Self::COMPRESSION_SHIFT) & Self::COMPRESSION_MASK,
+            )
+            .is_ok()
+    }
+
+    /// only meaningful for valid records
+    pub(crate) fn kind(&self) -> RecordKind {
+        if self.is_legacy() {
+            return RecordKind::Standalone;
+        }
+
+        RecordKind::try_from(self.header()[Self::HASH_SIZE] & Self::KIND_MASK).unwrap()
+    }
+
+    /// what the value is compressed with.
+    /// only meaningful for valid records
+    pub(crate) fn compression(&self) -> CompressionKind {
+        if self.is_legacy() {
+            return CompressionKind::None;
+        }
+
+        CompressionKind::try_from(
+            (self.header()[Self::HASH_SIZE] >> Self::COMPRESSION_SHIFT) & Self::COMPRESSION_MASK,
+        )
+        .unwrap()
+    }
+
+    /// the number of records committed by a `BatchCommit` record.
+    /// only meaningful for valid records
+    pub(crate) fn batch_len(&self) -> u32 {
+        u32::from_be_bytes(self.value_bytes().try_into().unwrap())
+    }
+
+    /// the same record, but taking effect on its own rather than as part of a batch.
+    /// merge uses this, as it only copies committed records.
+    pub(crate) fn into_standalone(mut self, encoding: &Encoding) -> crate::Result<Self> {
+        if self.kind() == RecordKind::Standalone {
+            return Ok(self);
+        }
+
+        // an encrypted value is sealed with its record's kind
+        if encoding.is_encrypted() && self.liveness() == Liveness::Live {
+            let header = self.value_header();
+
+            let value =
+                encoding.open_value(self.value_bytes().to_vec(), self.key_bytes(), header)?;
+
+            let header = ValueHeader {
+                kind: RecordKind::Standalone,
+                ..header
+            };
+
+            let value = encoding.seal_value(value, self.key_bytes(), header);
+
+            return Ok(Self::from_encoded(
+                self.size_fields,
+                header.kind,
+                header.compression,
+                header.tx_id,
+                header.timestamp,
+                header.expires_at,
+                self.key_bytes(),
+                &value,
+            ));
+        }
+
+        self.buf[Self::HASH_SIZE] &= !Self::KIND_MASK;
+        self.set_hash();
+
+        Ok(self)
+    }
+
+    /// The same record, as it is written to a data file of the current format version.
+    /// Merge uses this, so that merging migrates older data files.
+    pub(crate) fn into_current_format(mut self) -> Self {
+        if self.format_version < FileHeader::FLAGGED_DELETES_VERSION
+            && self.liveness() == Liveness::Deleted
+        {
+            return Self::delete(
+                self.size_fields,
+                self.kind(),
+                self.tx_id(),
+                self.timestamp(),
+                self.key_bytes(),
+            );
+        }
+
+        if self.is_legacy() {
+            return Self::from_encoded(
+                self.size_fields,
+                self.kind(),
+                self.compression(),
+                self.tx_id(),
+                self.timestamp(),
+                self.expires_at(),
+                self.key_bytes(),
+                self.value_bytes(),
+            );
+        }
+
+        self.format_version = FileHeader::CURRENT_VERSION;
+
+        self
+    }
+
+    /// The same record, with its value compressed with `compression` instead,
+    /// if it isn't already, and if that doesn't make the record larger.
+    /// Merge uses this, so that changing `Options::compression` eventually applies to every record
+    /// that it would make smaller.
+    pub(crate) fn recompress(
+        self,
+        compression: Compression,
+        threshold: usize,
+        encoding: &Encoding,
+    ) -> crate::Result<Self> {
+        if self.kind() == RecordKind::BatchCommit || self.liveness() == Liveness::Deleted {
+            return Ok(self);
+        }
+
+        if self.compression() == compression.kind() {
+            return Ok(self);
+        }
+
+        let header = self.value_header();
+
+        let value = encoding.open_value(self.value_bytes().to_vec(), self.key_bytes(), header)?;
+
+        let value = self.compression().decompress(value)?;
+
+        let (new_compression, new_value) = compression.compress(value, threshold)?;
+
+        if new_compression == self.compression() {
+            return Ok(self);
+        }
+
+        let new_value = encoding.seal_value(
+            new_value,
+            self.key_bytes(),
+            ValueHeader {
+                compression: new_compression,
+                ..header
+            },
+        );
+
+        // decompressing makes a value larger, possibly too large for its size field,
+        // and merge outputs are only sized for records that are no larger than they were
+        if new_value.len() > self.value_bytes().len() {
+            return Ok(self);
+        }
+
+        Ok(Self::from_encoded(
+            self.size_fields,
+            self.kind(),
+            new_compression,
+            self.tx_id(),
+            self.timestamp(),
+            self.expires_at(),
+            self.key_bytes(),
+            &new_value,
+        ))
     }
 
     pub(crate) fn liveness(&self) -> Liveness {
-        if self.value_bytes()
-            == SERIALIZED_TOMBSTONE.get_or_init(|| bincode::serialize(&TOMBSTONE_BYTES).unwrap())
+        if (!self.is_legacy() && self.header()[Self::HASH_SIZE] & Self::DELETE != 0)
+            || (self.format_version < FileHeader::FLAGGED_DELETES_VERSION
+                && self.compression() == CompressionKind::None
+                && self.value_bytes() == Self::legacy_tombstone())
         {
             Liveness::Deleted
         } else {
@@ -122,8 +493,15 @@ impl Record {
         }
     }
 
-    pub(crate) fn tombstone() -> &'static [u8] {
-        TOMBSTONE_BYTES
+    /// Before version 2, a delete was a record whose value was this,
+    /// which made inserting this value the same as deleting the key.
+    /// It is still recognized in version 0 and 1 data files, until merge migrates them.
+    pub(crate) fn legacy_tombstone() -> &'static [u8] {
+        SERIALIZED_LEGACY_TOMBSTONE.get_or_init(|| {
+            crate::codec::Bincode
+                .encode(LEGACY_TOMBSTONE_BYTES)
+                .expect("a byte slice always serializes")
+        })
     }
 
     pub(crate) fn key_bytes(&self) -> &[u8] {
@@ -142,36 +520,171 @@ impl Record {
         self.buf.len()
     }
 
+    pub(crate) fn size_fields(&self) -> SizeFields {
+        self.size_fields
+    }
+
+    /// where the value starts, relative to the start of the record
+    pub(crate) fn value_offset(&self) -> u64 {
+        Self::header_size_for(self.size_fields, self.format_version) as u64
+            + u64::from(self.key_size().0)
+    }
+
+    /// the length of a record with a key and value of the given sizes,
+    /// in a data file of the given format version,
+    /// for when only a pointer to the record is at hand
+    pub(crate) fn len_for(
+        size_fields: SizeFields,
+        format_version: u16,
+        key_size: u64,
+        value_size: &ValueSize,
+    ) -> u64 {
+        Self::header_size_for(size_fields, format_version) as u64 + key_size + value_size.0
+    }
+
     pub(crate) fn tx_id(&self) -> TxId {
         u128::from_be_bytes(self.tx_id_bytes().try_into().unwrap()).into()
     }
 
-    pub(crate) fn key_size(&self) -> KeySize {
-        KeySize(u16::from_be_bytes(
-            self.key_size_bytes().try_into().unwrap(),
+    /// version 0 records did not record when they were written, so they are from the epoch
+    pub(crate) fn timestamp(&self) -> Timestamp {
+        if self.is_legacy() {
+            return Timestamp(0);
+        }
+
+        Timestamp(u64::from_be_bytes(
+            self.timestamp_bytes().try_into().unwrap(),
         ))
     }
 
-    pub(crate) fn value_size(&self) -> ValueSize {
-        ValueSize(u32::from_be_bytes(
-            self.value_size_bytes().try_into().unwrap(),
+    pub(crate) fn expires_at(&self) -> ExpiresAt {
+        if self.is_legacy() {
+            return ExpiresAt::NEVER;
+        }
+
+        ExpiresAt(u64::from_be_bytes(
+            self.expires_at_bytes().try_into().unwrap(),
         ))
     }
+
+    /// the fields of the header that the value is sealed with.
+    /// only meaningful for valid records
+    pub(crate) fn value_header(&self) -> ValueHeader {
+        ValueHeader {
+            kind: self.kind(),
+            compression: self.compression(),
+            tx_id: self.tx_id(),
+            timestamp: self.timestamp(),
+            expires_at: self.expires_at(),
+        }
+    }
+
+    pub(crate) fn key_size(&self) -> KeySize {
+        self.size_fields.decode_key_size(self.key_size_bytes())
+    }
+
+    pub(crate) fn value_size(&self) -> ValueSize {
+        self.size_fields.decode_value_size(self.value_size_bytes())
+    }
 }
 
 // private impls
 impl Record {
     const HASH_SIZE: usize = std::mem::size_of::<u32>();
+    const KIND_SIZE: usize = std::mem::size_of::<RecordKind>();
     const TX_ID_SIZE: usize = std::mem::size_of::<TxId>();
-    const KEY_SIZE_SIZE: usize = std::mem::size_of::<KeySize>();
-    const VALUE_SIZE_SIZE: usize = std::mem::size_of::<ValueSize>();
+    const TIMESTAMP_SIZE: usize = std::mem::size_of::<Timestamp>();
+    const EXPIRES_AT_SIZE: usize = std::mem::size_of::<ExpiresAt>();
+    const KIND_MASK: u8 = 0x0f;
+    const COMPRESSION_SHIFT: u32 = 4;
+    const COMPRESSION_MASK: u8 = 0x03;
+    const DELETE: u8 = 0x40;
+    /// hash, tx_id, a 2-byte key size and a 4-byte value size
+    const LEGACY_HEADER_SIZE: usize = Self::HASH_SIZE
+        + Self::TX_ID_SIZE
+        + std::mem::size_of::<u16>()
+        + std::mem::size_of::<u32>();
+
+    fn kind_byte(kind: RecordKind, compression: CompressionKind) -> u8 {
+        kind as u8 | (compression as u8) << Self::COMPRESSION_SHIFT
+    }
+
+    fn build(
+        size_fields: SizeFields,
+        kind_byte: u8,
+        tx_id: TxId,
+        timestamp: Timestamp,
+        expires_at: ExpiresAt,
+        encoded_key: &[u8],
+        encoded_value: &[u8],
+    ) -> Self {
+        let encoded_tx_id = tx_id.to_be_bytes();
+
+        let key_size = encoded_key.len();
+        let value_size = encoded_value.len();
+        let body_size = key_size + value_size;
+
+        let encoded_key_size = size_fields.encode_key_size(key_size);
+        let encoded_value_size = size_fields.encode_value_size(value_size);
+
+        let mut buf = Vec::with_capacity(Self::header_size(size_fields) + body_size);
+        // header
+        // dummy hash bytes, added back in at the end...
+        buf.extend_from_slice(&[0u8; Self::HASH_SIZE]);
+        // rest of header
+        buf.push(kind_byte);
+        buf.extend_from_slice(&encoded_tx_id);
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+        buf.extend_from_slice(&expires_at.to_be_bytes());
+        buf.extend_from_slice(&encoded_key_size);
+        buf.extend_from_slice(&encoded_value_size);
+        // body
+        buf.extend_from_slice(encoded_key);
+        buf.extend_from_slice(encoded_value);
+
+        let mut record = Record {
+            buf,
+            size_fields,
+            format_version: FileHeader::CURRENT_VERSION,
+        };
+
+        record.set_hash();
+
+        record
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.format_version == FileHeader::LEGACY_VERSION
+    }
+
+    /// everything before the tx_id
+    fn tx_id_start(&self) -> usize {
+        if self.is_legacy() {
+            Self::HASH_SIZE
+        } else {
+            Self::HASH_SIZE + Self::KIND_SIZE
+        }
+    }
+
+    /// everything before the key size
+    fn key_size_start(&self) -> usize {
+        if self.is_legacy() {
+            Self::HASH_SIZE + Self::TX_ID_SIZE
+        } else {
+            Self::HASH_SIZE
+                + Self::KIND_SIZE
+                + Self::TX_ID_SIZE
+                + Self::TIMESTAMP_SIZE
+                + Self::EXPIRES_AT_SIZE
+        }
+    }
 
     fn header(&self) -> &[u8] {
-        &self.buf[..Self::HEADER_SIZE]
+        &self.buf[..Self::header_size_for(self.size_fields, self.format_version)]
     }
 
     fn body(&self) -> &[u8] {
-        &self.buf[Self::HEADER_SIZE..]
+        &self.buf[Self::header_size_for(self.size_fields, self.format_version)..]
     }
 
     fn hash_read_from_disk(&self) -> u32 {
@@ -180,42 +693,151 @@ impl Record {
     }
 
     fn computed_hash(&self) -> u32 {
-        let mut hasher = crc32fast::Hasher::new();
-
-        hasher.update(self.tx_id_bytes());
-        hasher.update(self.key_size_bytes());
-        hasher.update(self.value_size_bytes());
-        hasher.update(self.body());
+        crc32fast::hash(&self.buf[Self::HASH_SIZE..])
+    }
 
-        hasher.finalize()
+    fn set_hash(&mut self) {
+        let hash_bytes = self.computed_hash().to_be_bytes();
+        self.buf[..Self::HASH_SIZE].copy_from_slice(&hash_bytes);
     }
 
     fn tx_id_bytes(&self) -> &[u8] {
-        let start = Self::HASH_SIZE;
+        let start = self.tx_id_start();
         let end = start + Self::TX_ID_SIZE;
         &self.header()[start..end]
     }
 
+    fn timestamp_bytes(&self) -> &[u8] {
+        let start = Self::HASH_SIZE + Self::KIND_SIZE + Self::TX_ID_SIZE;
+        let end = start + Self::TIMESTAMP_SIZE;
+        &self.header()[start..end]
+    }
+
+    fn expires_at_bytes(&self) -> &[u8] {
+        let start = Self::HASH_SIZE + Self::KIND_SIZE + Self::TX_ID_SIZE + Self::TIMESTAMP_SIZE;
+        let end = start + Self::EXPIRES_AT_SIZE;
+        &self.header()[start..end]
+    }
+
     fn key_size_bytes(&self) -> &[u8] {
-        let start = Self::HASH_SIZE + Self::TX_ID_SIZE;
-        let end = start + Self::KEY_SIZE_SIZE;
+        let start = self.key_size_start();
+        let end = start + self.size_fields.key_size_size();
         &self.header()[start..end]
     }
 
     fn value_size_bytes(&self) -> &[u8] {
-        let start = Self::HASH_SIZE + Self::TX_ID_SIZE + Self::KEY_SIZE_SIZE;
-        let end = start + Self::VALUE_SIZE_SIZE;
+        let start = self.key_size_start() + self.size_fields.key_size_size();
+        let end = start + self.size_fields.value_size_size();
         &self.header()[start..end]
     }
 }
 
+/// The header fields of a record with a value, apart from its hash and sizes.
+/// An encrypted value is sealed with them, so changing any of them
+/// makes it fail to decrypt.
+/// The sizes are covered by the key and value themselves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ValueHeader {
+    pub(crate) kind: RecordKind,
+    pub(crate) compression: CompressionKind,
+    pub(crate) tx_id: TxId,
+    pub(crate) timestamp: Timestamp,
+    pub(crate) expires_at: ExpiresAt,
+}
+
+impl ValueHeader {
+    /// the fields as they are laid out in the header
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![Record::kind_byte(self.kind, self.compression)];
+        bytes.extend_from_slice(&self.tx_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes
+    }
+}
+
+/// in memory, sizes are as wide as `SizeFields::Wide`, whatever is on disk
 #[derive(PartialEq)]
-pub(crate) struct KeySize(pub(crate) u16);
+pub(crate) struct KeySize(pub(crate) u32);
 
-#[derive(Debug, PartialEq)]
-pub(crate) struct ValueSize(pub(crate) u32);
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ValueSize(pub(crate) u64);
 
+/// When a record was written, in milliseconds since the unix epoch.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Timestamp(u64);
+
+impl Timestamp {
+    pub(crate) fn now() -> Self {
+        Self(now_millis())
+    }
+
+    pub(crate) fn to_be_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub(crate) fn to_system_time(self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.0)
+    }
+}
+
+impl From<u64> for Timestamp {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+/// When a record stops being visible, in milliseconds since the unix epoch.
+/// Zero means never.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ExpiresAt(u64);
+
+impl ExpiresAt {
+    pub(crate) const NEVER: Self = Self(0);
+
+    /// `ttl` from now
+    pub(crate) fn after(ttl: Duration) -> Self {
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(ttl);
+
+        // zero means never, so the earliest possible expiry is the first millisecond
+        Self((expires_at.as_millis() as u64).max(1))
+    }
+
+    pub(crate) fn to_be_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    /// `None` if it never expires
+    pub(crate) fn to_system_time(self) -> Option<SystemTime> {
+        if self == Self::NEVER {
+            None
+        } else {
+            Some(UNIX_EPOCH + Duration::from_millis(self.0))
+        }
+    }
+
+    pub(crate) fn has_passed(&self) -> bool {
+        *self != Self::NEVER && self.0 <= now_millis()
+    }
+}
+
+impl From<u64> for ExpiresAt {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub(crate) struct TxId(u128);
 
 impl TxId {
@@ -243,3 +865,69 @@ impl AddAssign<u128> for TxId {
         self.0 += rhs
     }
 }
+
+impl Deref for TxId {
+    type Target = u128;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify, Options, WriteBatch, B2};
+
+    #[tokio::test]
+    async fn malformed_batch_commits_are_corrupt_records() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), 1u32).await.unwrap();
+
+        let data_file = dir.path().join("1");
+
+        let before_batch_len = tokio::fs::metadata(&data_file).await.unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        batch.insert("a".to_string(), 2u32).unwrap();
+        batch.insert("b".to_string(), 3u32).unwrap();
+
+        db.write_batch(batch).await.unwrap();
+
+        drop(db);
+
+        // a commit whose hash matches, but whose value is too short to be a length
+        let commit = Record::from_encoded(
+            SizeFields::Compact,
+            RecordKind::BatchCommit,
+            CompressionKind::None,
+            TxId::from(100),
+            Timestamp::now(),
+            ExpiresAt::NEVER,
+            &[],
+            &[0, 2],
+        );
+
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        bytes.truncate(bytes.len() - (Record::header_size(SizeFields::Compact) + 4));
+        let commit_offset = bytes.len() as u64;
+        bytes.extend_from_slice(&commit);
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        let report = verify(dir.path()).await.unwrap();
+        assert_eq!(report.files[0].corrupt_offsets, vec![commit_offset]);
+        assert_eq!(report.files[0].uncommitted, 2);
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        assert_eq!(db.get::<u32>(&"a".to_string()).await.unwrap(), Some(1));
+        assert!(!db.contains_key(&"b".to_string()).await);
+
+        let report = db.recovery_report().await;
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].offset, before_batch_len);
+    }
+}
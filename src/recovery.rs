@@ -0,0 +1,100 @@
+use crate::keydir::FileId;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncSeekExt;
+
+/// What was dropped while loading data files,
+/// according to the database's `RecoveryMode`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// every range of a data file that did not hold a valid record,
+    /// or held a batch that was never committed,
+    /// and was therefore not loaded
+    pub dropped: Vec<DroppedRange>,
+}
+
+impl RecoveryReport {
+    /// true if nothing was dropped
+    pub fn is_clean(&self) -> bool {
+        self.dropped.is_empty()
+    }
+}
+
+// crate-public impls
+impl RecoveryReport {
+    /// The batch from `offset` to `end` was dropped, as it was never committed,
+    /// or some of its records were corrupt.
+    /// The corrupt records are reported as part of it, rather than on their own.
+    pub(crate) fn drop_batch(&mut self, file_id: FileId, offset: u64, end: u64) {
+        self.dropped
+            .retain(|dropped| dropped.file_id != file_id || dropped.offset < offset);
+
+        self.dropped.push(DroppedRange {
+            file_id,
+            offset,
+            len: end - offset,
+            quarantined_to: None,
+        });
+    }
+}
+
+/// A range of bytes in a data file that did not hold a valid record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DroppedRange {
+    /// the data file the bytes were in
+    pub file_id: FileId,
+    /// the offset into the data file where the bytes started
+    pub offset: u64,
+    /// the number of bytes dropped
+    pub len: u64,
+    /// where the bytes were moved to, if they were removed from the data file
+    pub quarantined_to: Option<PathBuf>,
+}
+
+/// Moves everything in the given data file from `offset` onward
+/// into a `.corrupt` file next to it, and truncates the data file at `offset`.
+/// Returns the path of the `.corrupt` file.
+pub(crate) async fn quarantine_tail(
+    db_directory: &Path,
+    file_id: FileId,
+    offset: u64,
+) -> crate::Result<PathBuf> {
+    let mut data_file_path = db_directory.to_owned();
+    data_file_path.push(file_id.to_string());
+
+    let mut quarantine_path = db_directory.to_owned();
+    quarantine_path.push(format!("{}.corrupt", *file_id));
+
+    let mut data_file = tokio::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(&data_file_path)
+        .await?;
+
+    data_file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    // a file may be recovered more than once,
+    // so keep whatever was quarantined previously
+    let mut quarantine_file = tokio::fs::File::options()
+        .append(true)
+        .create(true)
+        .open(&quarantine_path)
+        .await?;
+
+    tokio::io::copy(&mut data_file, &mut quarantine_file).await?;
+
+    quarantine_file.sync_all().await?;
+
+    data_file.set_len(offset).await?;
+
+    data_file.sync_all().await?;
+
+    Ok(quarantine_path)
+}
+
+/// the length of the given data file, in bytes
+pub(crate) async fn data_file_len(db_directory: &Path, file_id: FileId) -> crate::Result<u64> {
+    let mut path = db_directory.to_owned();
+    path.push(file_id.to_string());
+
+    Ok(tokio::fs::metadata(path).await?.len())
+}
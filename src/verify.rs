@@ -0,0 +1,376 @@
+use crate::encoding::Encoding;
+use crate::file_header::FileHeader;
+use crate::keydir::{FileId, Liveness};
+use crate::loadable::PendingBatch;
+use crate::record::{Record, RecordKind, TxId};
+use crate::Options;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// The result of `verify`ing the data files in a database directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// one report per data file, in `FileId` order
+    pub files: Vec<FileReport>,
+    /// tx_ids that appear on more than one valid record
+    pub duplicate_tx_ids: Vec<u128>,
+}
+
+impl VerifyReport {
+    /// true if every record in every data file is intact
+    /// and no tx_id appears more than once
+    pub fn is_ok(&self) -> bool {
+        self.duplicate_tx_ids.is_empty()
+            && self
+                .files
+                .iter()
+                .all(|file| file.corrupt_offsets.is_empty() && file.torn_tail_offset.is_none())
+    }
+}
+
+/// What `verify` found in a single data file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileReport {
+    pub file_id: FileId,
+    /// the size of the data file, in bytes
+    pub len: u64,
+    /// the number of valid inserts and deletes,
+    /// not counting the records that mark the end of a batch
+    pub records: u64,
+    /// valid records that hold the latest value for their key
+    pub live: u64,
+    /// valid records that have been superseded by a later insert or delete
+    pub dead: u64,
+    /// valid records that are deletes
+    pub tombstones: u64,
+    /// valid records from batches that were never committed,
+    /// which do not take effect
+    pub uncommitted: u64,
+    /// the number of committed batches
+    pub batches: u64,
+    /// offsets of records whose hash does not match their contents
+    pub corrupt_offsets: Vec<u64>,
+    /// the offset of a record that runs past the end of the file, if any
+    pub torn_tail_offset: Option<u64>,
+    /// the smallest tx_id of any valid record
+    pub min_tx_id: Option<u128>,
+    /// the largest tx_id of any valid record
+    pub max_tx_id: Option<u128>,
+}
+
+/// Reads every record of every data file in `db_directory`
+/// and reports on their integrity.
+///
+/// A file that is not a data file, or that is in a format this version can't read,
+/// is an error rather than something to report on.
+///
+/// This does not change anything on disk, and it does not need
+/// to know the database's key type, as keys are compared as bytes.
+/// It does need to hold every key in memory, like opening the database does.
+///
+/// An encrypted database has to be verified with `verify_with` and its key,
+/// as its keys can't be compared without decrypting them.
+pub async fn verify(db_directory: &Path) -> crate::Result<VerifyReport> {
+    verify_with(db_directory, &Options::default()).await
+}
+
+/// `verify`, decrypting keys with `Options::encryption_key`
+/// before comparing them, and checking that values decrypt.
+/// The rest of `options` is not used.
+///
+/// Returns `Error::EncryptionMismatch` if a data file is encrypted and `options` has no key,
+/// or the other way around, and `Error::DecryptionFailed` if a key or value does not decrypt.
+pub async fn verify_with(db_directory: &Path, options: &Options) -> crate::Result<VerifyReport> {
+    let encoding = Encoding::new(options);
+
+    let mut db_file_ids = crate::base::all_db_file_ids(db_directory).await?;
+
+    db_file_ids.sort();
+
+    let mut files = Vec::with_capacity(db_file_ids.len());
+
+    // serialized key -> (tx_id, index into `files`, liveness) of its latest record
+    let mut latest: HashMap<Vec<u8>, (TxId, usize, Liveness)> = HashMap::new();
+
+    let mut seen_tx_ids: HashSet<TxId> = HashSet::new();
+
+    let mut duplicate_tx_ids: Vec<u128> = vec![];
+
+    for file_id in db_file_ids {
+        let mut path = db_directory.to_owned();
+        path.push(file_id.to_string());
+
+        let f = tokio::fs::File::open(&path).await?;
+
+        let len = f.metadata().await?.len();
+
+        let mut reader = tokio::io::BufReader::new(f);
+
+        let mut report = FileReport {
+            file_id,
+            len,
+            records: 0,
+            live: 0,
+            dead: 0,
+            tombstones: 0,
+            uncommitted: 0,
+            batches: 0,
+            corrupt_offsets: vec![],
+            torn_tail_offset: None,
+            min_tx_id: None,
+            max_tx_id: None,
+        };
+
+        let (mut offset, size_fields, format_version) =
+            match FileHeader::read_from(&mut reader, &path).await {
+                Ok(Some(header)) => {
+                    if header.is_encrypted() != encoding.is_encrypted() {
+                        return Err(crate::error::Error::EncryptionMismatch(path));
+                    }
+
+                    (header.records_start(), header.size_fields(), header.version)
+                }
+                Ok(None) => {
+                    files.push(report);
+                    continue;
+                }
+                Err(crate::error::Error::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    report.torn_tail_offset = Some(0);
+                    files.push(report);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+        let mut pending_batch = PendingBatch::new();
+
+        let file_index = files.len();
+
+        loop {
+            let record = match Record::read_from(&mut reader, size_fields, format_version).await {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    report.torn_tail_offset = Some(offset);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let record_offset = offset;
+
+            offset += record.len() as u64;
+
+            if !record.is_valid() {
+                report.corrupt_offsets.push(record_offset);
+                continue;
+            }
+
+            let tx_id = record.tx_id();
+
+            report.min_tx_id = Some(report.min_tx_id.map_or(*tx_id, |min| min.min(*tx_id)));
+            report.max_tx_id = Some(report.max_tx_id.map_or(*tx_id, |max| max.max(*tx_id)));
+
+            if !seen_tx_ids.insert(tx_id) {
+                duplicate_tx_ids.push(*tx_id);
+            }
+
+            // a value is sealed with its record's header,
+            // so this finds headers changed in ways that loading can't tell
+            if record.kind() != RecordKind::BatchCommit && record.liveness() == Liveness::Live {
+                encoding.open_value(
+                    record.value_bytes().to_vec(),
+                    record.key_bytes(),
+                    record.value_header(),
+                )?;
+            }
+
+            match record.kind() {
+                RecordKind::Standalone => {
+                    report.uncommitted += pending_batch.abandon() as u64;
+
+                    apply(
+                        &mut latest,
+                        &mut report,
+                        file_index,
+                        encoding.open_key(record.key_bytes())?.into_owned(),
+                        tx_id,
+                        record.liveness(),
+                    );
+                }
+                RecordKind::Batched => {
+                    pending_batch.push(
+                        record_offset,
+                        (
+                            encoding.open_key(record.key_bytes())?.into_owned(),
+                            tx_id,
+                            record.liveness(),
+                        ),
+                    );
+                }
+                RecordKind::BatchCommit => {
+                    let pending_len = pending_batch.len();
+
+                    if let Some(records) = pending_batch.commit(record.batch_len()) {
+                        report.batches += 1;
+
+                        for (key_bytes, tx_id, liveness) in records {
+                            apply(
+                                &mut latest,
+                                &mut report,
+                                file_index,
+                                key_bytes,
+                                tx_id,
+                                liveness,
+                            );
+                        }
+                    } else {
+                        report.uncommitted += pending_len as u64;
+                    }
+                }
+            }
+        }
+
+        report.uncommitted += pending_batch.abandon() as u64;
+        report.records += report.uncommitted;
+
+        files.push(report);
+    }
+
+    for (_tx_id, file_index, liveness) in latest.values() {
+        if *liveness == Liveness::Live {
+            files[*file_index].live += 1;
+        }
+    }
+
+    for file in files.iter_mut() {
+        file.dead = file.records - file.uncommitted - file.tombstones - file.live;
+    }
+
+    duplicate_tx_ids.sort();
+    duplicate_tx_ids.dedup();
+
+    Ok(VerifyReport {
+        files,
+        duplicate_tx_ids,
+    })
+}
+
+/// count a record that took effect
+fn apply(
+    latest: &mut HashMap<Vec<u8>, (TxId, usize, Liveness)>,
+    report: &mut FileReport,
+    file_index: usize,
+    key_bytes: Vec<u8>,
+    tx_id: TxId,
+    liveness: Liveness,
+) {
+    report.records += 1;
+
+    if liveness == Liveness::Deleted {
+        report.tombstones += 1;
+    }
+
+    match latest.get_mut(&key_bytes) {
+        Some(existing) if existing.0 < tx_id => {
+            *existing = (tx_id, file_index, liveness);
+        }
+        Some(_) => (),
+        None => {
+            latest.insert(key_bytes, (tx_id, file_index, liveness));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "encryption")]
+    use crate::EncryptionKey;
+    use crate::B2;
+
+    #[tokio::test]
+    async fn verify_reports_counts_and_corruption() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let db: B2<String> = B2::open(dir.path(), Options::default()).await.unwrap();
+
+        db.insert("a".to_string(), "1".to_string()).await.unwrap();
+        db.insert("a".to_string(), "2".to_string()).await.unwrap();
+        db.insert("b".to_string(), "3".to_string()).await.unwrap();
+        db.remove("b".to_string()).await.unwrap();
+        db.insert("c".to_string(), "4".to_string()).await.unwrap();
+
+        drop(db);
+
+        let report = verify(dir.path()).await.unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.files.len(), 1);
+
+        let first = &report.files[0];
+        assert_eq!(first.file_id, 1.into());
+        assert_eq!(first.records, 5);
+        assert_eq!(first.live, 2);
+        assert_eq!(first.dead, 2);
+        assert_eq!(first.tombstones, 1);
+        assert_eq!(first.min_tx_id, Some(2));
+        assert_eq!(first.max_tx_id, Some(6));
+
+        let data_file = dir.path().join("1");
+        let mut bytes = tokio::fs::read(&data_file).await.unwrap();
+        bytes[FileHeader::SIZE as usize + 10] ^= 0xff;
+        bytes.truncate(bytes.len() - 1);
+        tokio::fs::write(&data_file, &bytes).await.unwrap();
+
+        let report = verify(dir.path()).await.unwrap();
+
+        assert!(!report.is_ok());
+
+        let first = &report.files[0];
+        assert_eq!(first.corrupt_offsets, vec![FileHeader::SIZE]);
+        assert!(first.torn_tail_offset.is_some());
+        assert_eq!(first.records, 3);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn encrypted_databases_are_verified_with_their_key() {
+        let dir = temp_dir::TempDir::with_prefix("b2").unwrap();
+
+        let options = Options {
+            encryption_key: Some(EncryptionKey::new([1; 32])),
+            ..Default::default()
+        };
+
+        let db: B2<String> = B2::open(dir.path(), options.clone()).await.unwrap();
+        db.insert("a".to_string(), 1).await.unwrap();
+        db.insert("a".to_string(), 2).await.unwrap();
+        db.insert("b".to_string(), 3).await.unwrap();
+        db.close().await.unwrap();
+
+        // the two records for "a" have different bytes for the same key
+        let report = verify_with(dir.path(), &options).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files[0].records, 3);
+        assert_eq!(report.files[0].live, 2);
+        assert_eq!(report.files[0].dead, 1);
+
+        assert!(matches!(
+            verify(dir.path()).await,
+            Err(crate::error::Error::EncryptionMismatch(_))
+        ));
+
+        let wrong_key = Options {
+            encryption_key: Some(EncryptionKey::new([2; 32])),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            verify_with(dir.path(), &wrong_key).await,
+            Err(crate::error::Error::DecryptionFailed)
+        ));
+    }
+}
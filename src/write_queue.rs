@@ -0,0 +1,58 @@
+use crate::batch::BatchOp;
+use tokio::sync::oneshot;
+
+/// An insert, remove or batch, with its keys and values already serialized,
+/// waiting to be written.
+#[derive(Debug)]
+pub(crate) enum Write<K> {
+    Single(BatchOp<K>),
+    Batch(Vec<BatchOp<K>>),
+}
+
+/// where to send the result of a write
+pub(crate) type ResultSender = oneshot::Sender<crate::Result<()>>;
+
+/// a write, and where to send the result of writing it
+pub(crate) type Queued<K> = (Write<K>, ResultSender);
+
+/// Writes that are waiting for the active file.
+///
+/// This is how group commit works:
+/// every writer puts its write in the queue and waits for its result.
+/// The writer whose write starts a new group, because the queue was empty,
+/// starts a task that waits for the active file.
+/// When the task gets it, the task takes everything in the queue,
+/// writes it all with a single write and a single flush or sync,
+/// and sends every waiting writer its result.
+#[derive(Debug)]
+pub(crate) struct WriteQueue<K>(std::sync::Mutex<Vec<Queued<K>>>);
+
+impl<K> WriteQueue<K> {
+    pub(crate) fn new() -> Self {
+        Self(std::sync::Mutex::new(vec![]))
+    }
+
+    /// returns the receiver for the result of the write,
+    /// and whether the write starts a new group
+    pub(crate) fn push(&self, write: Write<K>) -> (oneshot::Receiver<crate::Result<()>>, bool) {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        let mut queue = self.0.lock().unwrap();
+
+        let starts_group = queue.is_empty();
+
+        queue.push((write, result_sender));
+
+        (result_receiver, starts_group)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// everything that has been queued, in the order it was queued
+    pub(crate) fn take(&self) -> Vec<Queued<K>> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}